@@ -1,25 +1,227 @@
 #![no_std]
+#![feature(alloc_error_handler)]
 
 extern crate alloc;
 
-use buddy_system_allocator::LockedHeap;
+mod slab;
+mod stats;
 
-// 堆大小
-const HEAP_SIZE: usize = 0x0008_0000;
+use core::alloc::{GlobalAlloc, Layout};
+use core::mem;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
-// 堆空间
+use buddy_system_allocator::{Heap, LockedHeapWithRescue};
+use log::error;
+use spin::Mutex;
+
+pub use slab::{dump_slab_stats, SlabBox, SlabCache, SlabStats, SIZE_CLASSES};
+pub use stats::{leak_scope, report_leaks, stats, HeapStats, LeakScope};
+
+/// 解析编译时环境变量中的数字，支持十进制、0x 开头的十六进制和 K/M/G 后缀
+const fn parse_size(value: Option<&str>, default: usize) -> usize {
+    let bytes = match value {
+        Some(value) => value.as_bytes(),
+        None => return default,
+    };
+    let (radix, mut i) = if bytes.len() > 2 && bytes[0] == b'0' && (bytes[1] | 0x20) == b'x' {
+        (16, 2)
+    } else {
+        (10, 0)
+    };
+    let mut result = 0;
+    while i < bytes.len() {
+        let digit = match bytes[i] {
+            b'_' => {
+                i += 1;
+                continue;
+            }
+            b'0'..=b'9' => bytes[i] - b'0',
+            b'a'..=b'f' if radix == 16 => bytes[i] - b'a' + 10,
+            b'A'..=b'F' if radix == 16 => bytes[i] - b'A' + 10,
+            _ => break,
+        };
+        result = result * radix + digit as usize;
+        i += 1;
+    }
+    let shift = if i == bytes.len() {
+        0
+    } else if i + 1 == bytes.len() {
+        match bytes[i] | 0x20 {
+            b'k' => 10,
+            b'm' => 20,
+            b'g' => 30,
+            _ => panic!("invalid size suffix, expect K, M or G"),
+        }
+    } else {
+        panic!("invalid size")
+    };
+    result << shift
+}
+
+/// 解析编译时环境变量中的十进制整数
+const fn parse_number(value: Option<&str>, default: usize) -> usize {
+    let bytes = match value {
+        Some(value) => value.as_bytes(),
+        None => return default,
+    };
+    let mut result = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'0'..=b'9' => result = result * 10 + (bytes[i] - b'0') as usize,
+            _ => panic!("invalid number"),
+        }
+        i += 1;
+    }
+    result
+}
+
+/// 堆大小，启动后由内核从页帧分配器中分配，
+/// 可以在编译时通过环境变量 HEAP_SIZE 设置，如 HEAP_SIZE=2M
+pub const HEAP_SIZE: usize = parse_size(option_env!("HEAP_SIZE"), 0x0008_0000);
+
+// 启动早期 (页帧分配器初始化之前) 使用的静态堆大小，
+// 可以在编译时通过环境变量 BOOT_HEAP_SIZE 设置
+const BOOT_HEAP_SIZE: usize = parse_size(option_env!("BOOT_HEAP_SIZE"), 0x0001_0000);
+
+// 伙伴分配器的阶数，能分配的最大内存块为 2^(HEAP_ORDER - 1) 字节，
+// 可以在编译时通过环境变量 HEAP_ORDER 设置
+const HEAP_ORDER: usize = parse_number(option_env!("HEAP_ORDER"), 30);
+
+// 启动早期的堆空间
 #[link_section = ".bss.heap"]
-static mut HEAP: [u8; HEAP_SIZE] = [0; HEAP_SIZE];
+static mut BOOT_HEAP: [u8; BOOT_HEAP_SIZE] = [0; BOOT_HEAP_SIZE];
+
+/// 堆扩展函数，参数为至少需要的字节数，返回新内存的起始地址和大小
+pub type GrowHook = fn(usize) -> Option<(usize, usize)>;
+
+/// 堆内存不足时调用的扩展函数
+static GROW_HOOK: Mutex<Option<GrowHook>> = Mutex::new(None);
+
+/// 伙伴分配器，大块内存和 slab 使用的内存都从这里分配
+///
+/// 初始使用 .bss.heap 中的静态空间，页帧分配器初始化后通过 init_with_region
+/// 加入 HEAP_SIZE 大小的堆，不够时再通过 GROW_HOOK 扩展
+pub(crate) static BUDDY_HEAP: LockedHeapWithRescue<HEAP_ORDER> = LockedHeapWithRescue::new(grow_heap);
 
 /// 堆内存分配器
 #[global_allocator]
-static HEAP_ALLOCATOR: LockedHeap<30> = LockedHeap::empty();
+static HEAP_ALLOCATOR: HeapAllocator = HeapAllocator;
+
+/// 小于等于 2048 字节的分配交给对应大小的 slab，其余的交给伙伴分配器
+struct HeapAllocator;
 
-/// 初始化堆内存分配器
+unsafe impl GlobalAlloc for HeapAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        without_interrupts(|| {
+            let ptr = match slab::size_class_index(&layout) {
+                Some(index) => slab::SIZE_CLASS_SLABS[index].lock().alloc(),
+                None => BUDDY_HEAP.alloc(layout),
+            };
+            if !ptr.is_null() {
+                stats::record_alloc(ptr, layout.size());
+            }
+            ptr
+        })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        without_interrupts(|| {
+            stats::record_dealloc(ptr, layout.size());
+            match slab::size_class_index(&layout) {
+                Some(index) => slab::SIZE_CLASS_SLABS[index].lock().dealloc(ptr),
+                None => BUDDY_HEAP.dealloc(ptr, layout),
+            }
+        })
+    }
+}
+
+/// 关闭中断，返回之前中断是否打开
+pub type IrqDisableHook = fn() -> bool;
+
+/// 恢复 IrqDisableHook 返回的中断状态
+pub type IrqRestoreHook = fn(bool);
+
+// 分配内存时会读取这两个函数，不能用锁保护，所以保存为函数地址，0 表示没有设置
+static IRQ_DISABLE_HOOK: AtomicUsize = AtomicUsize::new(0);
+static IRQ_RESTORE_HOOK: AtomicUsize = AtomicUsize::new(0);
+
+/// 关闭中断执行 f，没有设置中断函数时直接执行
+///
+/// 持有堆的锁时如果被时钟中断抢占，切换到的任务或中断处理函数再分配内存就会死锁
+#[inline]
+pub(crate) fn without_interrupts<T>(f: impl FnOnce() -> T) -> T {
+    let disable = IRQ_DISABLE_HOOK.load(Ordering::Acquire);
+    if disable == 0 {
+        return f();
+    }
+    let (disable, restore) = unsafe {
+        (
+            mem::transmute::<usize, IrqDisableHook>(disable),
+            mem::transmute::<usize, IrqRestoreHook>(IRQ_RESTORE_HOOK.load(Ordering::Relaxed)),
+        )
+    };
+    let enabled = disable();
+    let ret = f();
+    restore(enabled);
+    ret
+}
+
+/// 堆内存不足时扩展堆
+fn grow_heap(heap: &mut Heap<HEAP_ORDER>, layout: &Layout) {
+    let hook = *GROW_HOOK.lock();
+    if let Some((start, size)) = hook.and_then(|grow| grow(layout.size().max(layout.align()))) {
+        unsafe { heap.add_to_heap(start, start + size) };
+    }
+}
+
+/// 使用 .bss.heap 中的静态空间初始化堆内存分配器，只够启动早期使用
 pub fn init() {
     unsafe {
-        HEAP_ALLOCATOR
+        BUDDY_HEAP
             .lock()
-            .init(HEAP.as_mut_ptr() as usize, HEAP_SIZE);
+            .init(BOOT_HEAP.as_mut_ptr() as usize, BOOT_HEAP_SIZE);
     }
 }
+
+/// 把 [start, start + size) 加入堆
+///
+/// 在 init 之后调用，把堆的主要空间放到页帧分配器提供的内存中
+///
+/// # Safety
+///
+/// 这段内存必须可以读写，并且不能再被其他地方使用
+pub unsafe fn init_with_region(start: usize, size: usize) {
+    BUDDY_HEAP.lock().add_to_heap(start, start + size);
+}
+
+/// 设置开关中断的函数，通常在 init 之后、打开中断之前调用
+pub fn set_irq_hooks(disable: IrqDisableHook, restore: IrqRestoreHook) {
+    IRQ_RESTORE_HOOK.store(restore as usize, Ordering::Relaxed);
+    IRQ_DISABLE_HOOK.store(disable as usize, Ordering::Release);
+}
+
+/// 设置堆扩展函数，通常在页帧分配器初始化完成后调用
+pub fn set_grow_hook(hook: GrowHook) {
+    *GROW_HOOK.lock() = Some(hook);
+}
+
+/// 内存分配失败
+#[alloc_error_handler]
+fn alloc_error_handler(layout: Layout) -> ! {
+    let heap = BUDDY_HEAP.lock();
+    error!(
+        "heap allocation failed, size: {:#x}, align: {:#x}",
+        layout.size(),
+        layout.align()
+    );
+    error!(
+        "heap total: {:#x} bytes, allocated: {:#x} bytes (requested {:#x} bytes)",
+        heap.stats_total_bytes(),
+        heap.stats_alloc_actual(),
+        heap.stats_alloc_user()
+    );
+    drop(heap);
+    dump_slab_stats();
+    panic!("out of heap memory");
+}
@@ -0,0 +1,268 @@
+//! slab 分配器
+//!
+//! 把从伙伴分配器申请的整块内存切分成大小相同的对象，空闲对象串成链表。
+//! 小对象不再被伙伴分配器向上取整到 2 的幂，也不会把大块内存切碎。
+//! slab 的内存申请后不会再还给伙伴分配器。
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    marker::PhantomData,
+    mem::{align_of, size_of},
+    ops::{Deref, DerefMut},
+    ptr::{null_mut, NonNull},
+};
+
+use log::info;
+use spin::Mutex;
+
+use crate::{without_interrupts, BUDDY_HEAP};
+
+/// 通用 slab 的对象大小
+pub const SIZE_CLASSES: [usize; 8] = [16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// 每个 slab 至少占用的内存大小
+const MIN_SLAB_SIZE: usize = 0x1000;
+
+/// 每个 slab 至少容纳的对象数
+const MIN_OBJECTS_PER_SLAB: usize = 8;
+
+/// slab 使用情况
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SlabStats {
+    /// 对象大小
+    pub object_size: usize,
+    /// 从伙伴分配器申请的 slab 数量
+    pub slabs: usize,
+    /// 所有 slab 中的对象总数
+    pub total_objects: usize,
+    /// 正在使用的对象数
+    pub used_objects: usize,
+    /// 累计分配次数
+    pub alloc_count: usize,
+    /// 累计释放次数
+    pub free_count: usize,
+}
+
+/// 空闲对象，对象空闲时开头保存下一个空闲对象的地址
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+/// 不区分类型的 slab，只关心对象大小和对齐
+pub(crate) struct RawSlab {
+    object_size: usize,
+    align: usize,
+    free_list: *mut FreeObject,
+    stats: SlabStats,
+}
+
+// 空闲链表中的指针只在持有锁时访问
+unsafe impl Send for RawSlab {}
+
+impl RawSlab {
+    /// 创建一个 slab，对象大小至少能放下一个指针
+    pub const fn new(object_size: usize, align: usize) -> Self {
+        let align = if align > align_of::<FreeObject>() {
+            align
+        } else {
+            align_of::<FreeObject>()
+        };
+        let object_size = if object_size > size_of::<FreeObject>() {
+            object_size
+        } else {
+            size_of::<FreeObject>()
+        };
+        let object_size = (object_size + align - 1) / align * align;
+        Self {
+            object_size,
+            align,
+            free_list: null_mut(),
+            stats: SlabStats {
+                object_size,
+                slabs: 0,
+                total_objects: 0,
+                used_objects: 0,
+                alloc_count: 0,
+                free_count: 0,
+            },
+        }
+    }
+
+    /// 每个 slab 占用的内存大小
+    fn slab_size(&self) -> usize {
+        (self.object_size * MIN_OBJECTS_PER_SLAB)
+            .next_power_of_two()
+            .max(MIN_SLAB_SIZE)
+    }
+
+    /// 从伙伴分配器申请一个新的 slab 并加入空闲链表
+    fn grow(&mut self) -> bool {
+        let slab_size = self.slab_size();
+        let layout = match Layout::from_size_align(slab_size, slab_size.max(self.align)) {
+            Ok(layout) => layout,
+            Err(_) => return false,
+        };
+        let start = unsafe { BUDDY_HEAP.alloc(layout) };
+        if start.is_null() {
+            return false;
+        }
+        let count = slab_size / self.object_size;
+        for i in (0..count).rev() {
+            let object = unsafe { start.add(i * self.object_size) } as *mut FreeObject;
+            unsafe { object.write(FreeObject { next: self.free_list }) };
+            self.free_list = object;
+        }
+        self.stats.slabs += 1;
+        self.stats.total_objects += count;
+        true
+    }
+
+    /// 分配一个对象，内存不足时返回空指针
+    pub fn alloc(&mut self) -> *mut u8 {
+        if self.free_list.is_null() && !self.grow() {
+            return null_mut();
+        }
+        let object = self.free_list;
+        self.free_list = unsafe { (*object).next };
+        self.stats.used_objects += 1;
+        self.stats.alloc_count += 1;
+        object as *mut u8
+    }
+
+    /// 释放一个对象
+    ///
+    /// # Safety
+    ///
+    /// ptr 必须是由当前 slab 分配且没有被释放的对象
+    pub unsafe fn dealloc(&mut self, ptr: *mut u8) {
+        let object = ptr as *mut FreeObject;
+        object.write(FreeObject {
+            next: self.free_list,
+        });
+        self.free_list = object;
+        self.stats.used_objects -= 1;
+        self.stats.free_count += 1;
+    }
+
+    pub fn stats(&self) -> SlabStats {
+        self.stats
+    }
+}
+
+/// 通用 slab，由全局分配器使用
+pub(crate) static SIZE_CLASS_SLABS: [Mutex<RawSlab>; SIZE_CLASSES.len()] = [
+    Mutex::new(RawSlab::new(SIZE_CLASSES[0], SIZE_CLASSES[0])),
+    Mutex::new(RawSlab::new(SIZE_CLASSES[1], SIZE_CLASSES[1])),
+    Mutex::new(RawSlab::new(SIZE_CLASSES[2], SIZE_CLASSES[2])),
+    Mutex::new(RawSlab::new(SIZE_CLASSES[3], SIZE_CLASSES[3])),
+    Mutex::new(RawSlab::new(SIZE_CLASSES[4], SIZE_CLASSES[4])),
+    Mutex::new(RawSlab::new(SIZE_CLASSES[5], SIZE_CLASSES[5])),
+    Mutex::new(RawSlab::new(SIZE_CLASSES[6], SIZE_CLASSES[6])),
+    Mutex::new(RawSlab::new(SIZE_CLASSES[7], SIZE_CLASSES[7])),
+];
+
+/// 查找能容纳 layout 的通用 slab 下标
+///
+/// 对象大小都是 2 的幂，slab 按对象大小对齐，所以对象也满足对齐要求
+pub(crate) fn size_class_index(layout: &Layout) -> Option<usize> {
+    let size = layout.size().max(layout.align());
+    SIZE_CLASSES.iter().position(|class| *class >= size)
+}
+
+/// 输出所有通用 slab 的使用情况
+pub fn dump_slab_stats() {
+    info!(
+        "{:>8} {:>6} {:>8} {:>8} {:>10} {:>10}",
+        "size", "slabs", "total", "used", "allocs", "frees"
+    );
+    for slab in SIZE_CLASS_SLABS.iter() {
+        log_stats(&slab.lock().stats());
+    }
+}
+
+fn log_stats(stats: &SlabStats) {
+    info!(
+        "{:>8} {:>6} {:>8} {:>8} {:>10} {:>10}",
+        stats.object_size,
+        stats.slabs,
+        stats.total_objects,
+        stats.used_objects,
+        stats.alloc_count,
+        stats.free_count
+    );
+}
+
+/// 存放类型 T 的专用 slab
+///
+/// ```ignore
+/// static TASK_CACHE: SlabCache<Task> = SlabCache::new("task");
+/// let task = TASK_CACHE.alloc(Task::new()).unwrap();
+/// ```
+pub struct SlabCache<T> {
+    name: &'static str,
+    inner: Mutex<RawSlab>,
+    _marker: PhantomData<T>,
+}
+
+// 对象通过 SlabBox 访问，SlabCache 本身只管理内存
+unsafe impl<T: Send> Sync for SlabCache<T> {}
+
+impl<T> SlabCache<T> {
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            inner: Mutex::new(RawSlab::new(size_of::<T>(), align_of::<T>())),
+            _marker: PhantomData,
+        }
+    }
+
+    /// 分配一个对象并写入 value，内存不足时返回 None
+    pub fn alloc(&'static self, value: T) -> Option<SlabBox<T>> {
+        let ptr = NonNull::new(without_interrupts(|| self.inner.lock().alloc()) as *mut T)?;
+        unsafe { ptr.as_ptr().write(value) };
+        Some(SlabBox { ptr, cache: self })
+    }
+
+    pub fn stats(&self) -> SlabStats {
+        self.inner.lock().stats()
+    }
+
+    /// 输出使用情况
+    pub fn dump_stats(&self) {
+        info!("slab cache {}:", self.name);
+        log_stats(&self.stats());
+    }
+}
+
+/// 从 SlabCache 中分配的对象，离开作用域时释放
+pub struct SlabBox<T: 'static> {
+    ptr: NonNull<T>,
+    cache: &'static SlabCache<T>,
+}
+
+unsafe impl<T: Send> Send for SlabBox<T> {}
+unsafe impl<T: Sync> Sync for SlabBox<T> {}
+
+impl<T> Deref for SlabBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> DerefMut for SlabBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T> Drop for SlabBox<T> {
+    fn drop(&mut self) {
+        unsafe {
+            self.ptr.as_ptr().drop_in_place();
+            let ptr = self.ptr.as_ptr() as *mut u8;
+            without_interrupts(|| self.cache.inner.lock().dealloc(ptr));
+        }
+    }
+}
@@ -0,0 +1,188 @@
+//! 堆使用统计和内存泄漏追踪
+//!
+//! 开启 leak-tracking 特性后，在 leak_scope 返回的 guard 存活期间分配的内存
+//! 会记录调用 leak_scope 的位置，report_leaks 时输出所有还没有释放的记录。
+//! 标签只能区分不同的 leak_scope，作用域应该只包含一个启动阶段或一次操作。
+
+use core::{
+    panic::Location,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use log::info;
+
+use crate::BUDDY_HEAP;
+
+/// 堆使用情况
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    /// 伙伴分配器管理的总字节数
+    pub total_bytes: usize,
+    /// 正在使用的字节数 (按申请大小计算)
+    pub used_bytes: usize,
+    /// 使用字节数的峰值
+    pub peak_bytes: usize,
+    /// 累计分配次数
+    pub alloc_count: usize,
+    /// 累计释放次数
+    pub free_count: usize,
+}
+
+static USED_BYTES: AtomicUsize = AtomicUsize::new(0);
+static PEAK_BYTES: AtomicUsize = AtomicUsize::new(0);
+static ALLOC_COUNT: AtomicUsize = AtomicUsize::new(0);
+static FREE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// 记录一次分配
+pub(crate) fn record_alloc(ptr: *mut u8, size: usize) {
+    let used = USED_BYTES.fetch_add(size, Ordering::Relaxed) + size;
+    PEAK_BYTES.fetch_max(used, Ordering::Relaxed);
+    ALLOC_COUNT.fetch_add(1, Ordering::Relaxed);
+    #[cfg(feature = "leak-tracking")]
+    leak::record_alloc(ptr, size);
+    #[cfg(not(feature = "leak-tracking"))]
+    let _ = ptr;
+}
+
+/// 记录一次释放
+pub(crate) fn record_dealloc(ptr: *mut u8, size: usize) {
+    USED_BYTES.fetch_sub(size, Ordering::Relaxed);
+    FREE_COUNT.fetch_add(1, Ordering::Relaxed);
+    #[cfg(feature = "leak-tracking")]
+    leak::record_dealloc(ptr);
+    #[cfg(not(feature = "leak-tracking"))]
+    let _ = ptr;
+}
+
+/// 堆使用情况
+pub fn stats() -> HeapStats {
+    HeapStats {
+        total_bytes: BUDDY_HEAP.lock().stats_total_bytes(),
+        used_bytes: USED_BYTES.load(Ordering::Relaxed),
+        peak_bytes: PEAK_BYTES.load(Ordering::Relaxed),
+        alloc_count: ALLOC_COUNT.load(Ordering::Relaxed),
+        free_count: FREE_COUNT.load(Ordering::Relaxed),
+    }
+}
+
+/// leak_scope 返回的 guard，离开作用域时恢复之前的标签
+pub struct LeakScope {
+    #[cfg_attr(not(feature = "leak-tracking"), allow(dead_code))]
+    prev: Option<&'static Location<'static>>,
+}
+
+/// 之后分配的内存都标记为调用者的位置，直到返回值离开作用域
+///
+/// 没有开启 leak-tracking 特性时什么都不做
+#[track_caller]
+pub fn leak_scope() -> LeakScope {
+    #[cfg(feature = "leak-tracking")]
+    let prev = leak::CURRENT_TAG.lock().replace(Location::caller());
+    #[cfg(not(feature = "leak-tracking"))]
+    let prev = None;
+    LeakScope { prev }
+}
+
+impl Drop for LeakScope {
+    fn drop(&mut self) {
+        #[cfg(feature = "leak-tracking")]
+        {
+            *leak::CURRENT_TAG.lock() = self.prev;
+        }
+    }
+}
+
+/// 输出堆使用情况，开启 leak-tracking 时还会输出还没有释放的带标签分配
+pub fn report_leaks() {
+    let stats = stats();
+    info!(
+        "heap: total {:#x} bytes, used {:#x} bytes, peak {:#x} bytes, {} allocs, {} frees",
+        stats.total_bytes, stats.used_bytes, stats.peak_bytes, stats.alloc_count, stats.free_count
+    );
+    #[cfg(feature = "leak-tracking")]
+    leak::report();
+}
+
+#[cfg(feature = "leak-tracking")]
+mod leak {
+    use core::panic::Location;
+
+    use log::{info, warn};
+    use spin::Mutex;
+
+    /// 最多记录的分配数量
+    const MAX_RECORDS: usize = 1024;
+
+    /// 一次带标签的分配
+    #[derive(Clone, Copy)]
+    struct Record {
+        ptr: usize,
+        size: usize,
+        tag: &'static Location<'static>,
+    }
+
+    struct Records {
+        records: [Option<Record>; MAX_RECORDS],
+        /// 记录表满了之后丢弃的记录数
+        dropped: usize,
+    }
+
+    /// 当前的标签
+    pub(super) static CURRENT_TAG: Mutex<Option<&'static Location<'static>>> = Mutex::new(None);
+
+    /// 记录表，不能使用堆内存
+    static RECORDS: Mutex<Records> = Mutex::new(Records {
+        records: [None; MAX_RECORDS],
+        dropped: 0,
+    });
+
+    pub(super) fn record_alloc(ptr: *mut u8, size: usize) {
+        let tag = match *CURRENT_TAG.lock() {
+            Some(tag) if !ptr.is_null() => tag,
+            _ => return,
+        };
+        let mut records = RECORDS.lock();
+        match records.records.iter_mut().find(|x| x.is_none()) {
+            Some(slot) => {
+                *slot = Some(Record {
+                    ptr: ptr as usize,
+                    size,
+                    tag,
+                })
+            }
+            None => records.dropped += 1,
+        }
+    }
+
+    pub(super) fn record_dealloc(ptr: *mut u8) {
+        let mut records = RECORDS.lock();
+        if let Some(slot) = records
+            .records
+            .iter_mut()
+            .find(|x| matches!(x, Some(record) if record.ptr == ptr as usize))
+        {
+            *slot = None;
+        }
+    }
+
+    pub(super) fn report() {
+        let records = RECORDS.lock();
+        let leaks = records.records.iter().flatten();
+        let (count, bytes) = leaks
+            .clone()
+            .fold((0, 0), |(count, bytes), record| (count + 1, bytes + record.size));
+        info!("leak report: {} allocations, {:#x} bytes still alive", count, bytes);
+        for record in leaks {
+            warn!(
+                "  {:#x} {:>8} bytes  {}:{}",
+                record.ptr,
+                record.size,
+                record.tag.file(),
+                record.tag.line()
+            );
+        }
+        if records.dropped != 0 {
+            warn!("leak report: {} records dropped, table is full", records.dropped);
+        }
+    }
+}
@@ -0,0 +1,37 @@
+//! 生成 OUT_DIR/programs.rs，其中是编译时嵌入内核的用户程序表
+//!
+//! 环境变量 INIT_ELF 指定第一个用户程序，名称为 init；USER_PROGRAMS 指定其他程序，
+//! 多个路径用 `:` 分隔，名称为文件名。都没有设置时程序表为空，内核启动时不会运行用户程序
+
+use std::{env, fmt::Write, fs, path::Path, path::PathBuf};
+
+fn main() {
+    let out = PathBuf::from(env::var("OUT_DIR").unwrap()).join("programs.rs");
+    println!("cargo:rerun-if-env-changed=INIT_ELF");
+    println!("cargo:rerun-if-env-changed=USER_PROGRAMS");
+
+    let mut programs = Vec::new();
+    if let Ok(path) = env::var("INIT_ELF") {
+        programs.push((String::from("init"), path));
+    }
+    if let Ok(paths) = env::var("USER_PROGRAMS") {
+        for path in paths.split(':').filter(|path| !path.is_empty()) {
+            let name = Path::new(path)
+                .file_name()
+                .unwrap_or_else(|| panic!("invalid program path {}", path))
+                .to_string_lossy()
+                .into_owned();
+            programs.push((name, path.to_string()));
+        }
+    }
+
+    let mut code = String::from("/// 编译时嵌入的用户程序，(名称, ELF 文件)\n");
+    code.push_str("static PROGRAMS: &[(&str, &[u8])] = &[\n");
+    for (name, path) in programs {
+        let path = fs::canonicalize(&path).unwrap_or_else(|err| panic!("can't find {}: {}", path, err));
+        println!("cargo:rerun-if-changed={}", path.display());
+        writeln!(code, "    ({:?}, include_bytes!({:?})),", name, path).unwrap();
+    }
+    code.push_str("];\n");
+    fs::write(out, code).unwrap();
+}
@@ -0,0 +1,55 @@
+//! 墙上时钟与单调时钟
+//!
+//! 单调时钟由 time 寄存器和时钟频率换算得到。启动时读取一次 RTC，
+//! 记录此时墙上时间与单调时钟的差值，之后的墙上时间都由单调时钟加上差值得到，
+//! 不需要每次都访问 RTC。
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+use log::{info, warn};
+
+use crate::rtc::{self, NANOS_PER_SEC};
+use crate::timer;
+
+/// 墙上时间减去单调时钟的纳秒数
+static REALTIME_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// 启动以来经过的纳秒数
+fn monotonic_nanos() -> u64 {
+    (timer::get_time() as u128 * NANOS_PER_SEC as u128 / timer::timebase_freq() as u128) as u64
+}
+
+/// 从 RTC 读取墙上时间，没有 RTC 时墙上时间从 1970 年开始
+///
+/// 需要在 timer::init 和 rtc::init 之后调用
+pub fn init() {
+    let Some(now) = rtc::time_nanos() else {
+        warn!("clock: no rtc, realtime starts at the epoch");
+        return;
+    };
+    REALTIME_OFFSET.store(now.saturating_sub(monotonic_nanos()), Ordering::Relaxed);
+    info!("clock: realtime {:?} since the epoch", realtime());
+}
+
+/// 启动以来经过的时间 (纳秒精度)
+pub fn monotonic() -> Duration {
+    Duration::from_nanos(monotonic_nanos())
+}
+
+/// 自 1970 年以来的时间
+pub fn realtime() -> Duration {
+    Duration::from_nanos(REALTIME_OFFSET.load(Ordering::Relaxed).saturating_add(monotonic_nanos()))
+}
+
+/// 设置墙上时间，同时写入 RTC
+///
+/// 时间的纳秒数超出 u64 (2554 年之后) 时返回 false
+pub fn set_realtime(time: Duration) -> bool {
+    let Ok(nanos) = u64::try_from(time.as_nanos()) else {
+        return false;
+    };
+    rtc::set_time_nanos(nanos);
+    REALTIME_OFFSET.store(nanos.saturating_sub(monotonic_nanos()), Ordering::Relaxed);
+    true
+}
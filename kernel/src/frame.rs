@@ -1,64 +1,453 @@
-use alloc::vec::Vec;
-use log::info;
+use alloc::{sync::Arc, vec::Vec};
+use core::fmt::{self, Display};
+use log::{debug, error, info, warn};
 use spin::Mutex;
 
+use crate::page_table::{paddr_to_virt, PAGE_SIZE};
+use crate::trap::without_interrupts;
+
 /// FrameAllocator 页帧分配器
 /// 知道有哪些页，知道页是否被分配，能分配页
+///
+/// 可以管理多段不连续的物理内存，每段内存用位图记录页的使用情况，
+/// 分配时从上一次分配的位置继续向后查找 (next-fit)。
 
-pub struct FrameAllocator {
+/// 一段连续的物理内存
+struct FrameRegion {
+    /// 起始物理地址，按页对齐
     start: usize,
-    size: usize,
-    usage: Vec<bool>
+    /// 页数
+    pages: usize,
+    /// 空闲页数
+    free: usize,
+    /// 位图，1 表示已经被分配
+    bitmap: Vec<u64>,
+}
+
+impl FrameRegion {
+    fn new(start: usize, pages: usize) -> Self {
+        let mut bitmap = vec![0u64; (pages + 63) / 64];
+        // 最后一个字中超出范围的位标记为已使用，查找时就不需要额外判断
+        if pages % 64 != 0 {
+            bitmap[pages / 64] = !0 << (pages % 64);
+        }
+        Self {
+            start,
+            pages,
+            free: pages,
+            bitmap,
+        }
+    }
+
+    fn contains(&self, paddr: usize) -> bool {
+        paddr >= self.start && paddr < self.start + self.pages * PAGE_SIZE
+    }
+
+    fn is_used(&self, index: usize) -> bool {
+        self.bitmap[index / 64] & (1 << (index % 64)) != 0
+    }
+
+    fn set_used(&mut self, index: usize, used: bool) {
+        if used {
+            self.bitmap[index / 64] |= 1 << (index % 64);
+            self.free -= 1;
+        } else {
+            self.bitmap[index / 64] &= !(1 << (index % 64));
+            self.free += 1;
+        }
+    }
+
+    /// 在 [from, to) 中查找第一个空闲页
+    fn find_free(&self, from: usize, to: usize) -> Option<usize> {
+        let mut index = from;
+        while index < to {
+            let free = !self.bitmap[index / 64] & (!0 << (index % 64));
+            if free != 0 {
+                let found = index / 64 * 64 + free.trailing_zeros() as usize;
+                return (found < to).then_some(found);
+            }
+            index = (index / 64 + 1) * 64;
+        }
+        None
+    }
+
+    /// 在 [from, to) 中查找第一个已使用的页
+    fn find_used(&self, from: usize, to: usize) -> Option<usize> {
+        let mut index = from;
+        while index < to {
+            let used = self.bitmap[index / 64] & (!0 << (index % 64));
+            if used != 0 {
+                let found = index / 64 * 64 + used.trailing_zeros() as usize;
+                return (found < to).then_some(found);
+            }
+            index = (index / 64 + 1) * 64;
+        }
+        None
+    }
+
+    /// 从 hint 开始查找并分配一个页，返回页下标
+    fn alloc(&mut self, hint: usize) -> Option<usize> {
+        if self.free == 0 {
+            return None;
+        }
+        let index = self
+            .find_free(hint, self.pages)
+            .or_else(|| self.find_free(0, hint))?;
+        self.set_used(index, true);
+        Some(index)
+    }
+
+    /// 分配 count 个连续的页，起始物理地址按 align 对齐，返回起始页下标
+    fn alloc_contiguous(&mut self, count: usize, align: usize) -> Option<usize> {
+        if self.free < count {
+            return None;
+        }
+        let start = self.start;
+        let align_index = |index: usize| {
+            let paddr = start + index * PAGE_SIZE;
+            (paddr + align - 1) / align * align / PAGE_SIZE - start / PAGE_SIZE
+        };
+        let mut index = align_index(0);
+        while index + count <= self.pages {
+            match self.find_used(index, index + count) {
+                Some(used) => index = align_index(used + 1),
+                None => {
+                    (index..index + count).for_each(|i| self.set_used(i, true));
+                    return Some(index);
+                }
+            }
+        }
+        None
+    }
+}
+
+/// 页帧分配错误
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameError {
+    /// 没有足够的空闲页帧
+    OutOfMemory,
+    /// 释放的地址没有按页对齐
+    NotAligned,
+    /// 释放的地址不属于页帧分配器
+    InvalidAddress,
+    /// 释放一个没有被分配的页帧
+    DoubleFree,
+}
+
+impl Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::OutOfMemory => f.write_str("out of memory"),
+            FrameError::NotAligned => f.write_str("address is not page aligned"),
+            FrameError::InvalidAddress => f.write_str("address is not managed by frame allocator"),
+            FrameError::DoubleFree => f.write_str("double free"),
+        }
+    }
+}
+
+/// 释放页帧时填充的值，用于检测释放后使用
+#[cfg(feature = "frame-poison")]
+const POISON: u64 = 0xdead_beef_dead_beef;
+
+/// 用 POISON 填充页帧
+#[cfg(feature = "frame-poison")]
+fn poison_frame(paddr: usize) {
+    unsafe {
+        core::slice::from_raw_parts_mut(paddr_to_virt(paddr) as *mut u64, PAGE_SIZE / 8)
+            .fill(POISON);
+    }
+}
+
+/// 检查页帧释放后是否被修改
+#[cfg(feature = "frame-poison")]
+fn check_poison(paddr: usize) {
+    let page =
+        unsafe { core::slice::from_raw_parts(paddr_to_virt(paddr) as *const u64, PAGE_SIZE / 8) };
+    if let Some(offset) = page.iter().position(|x| *x != POISON) {
+        error!(
+            "frame allocator: use after free detected at {:#x}",
+            paddr + offset * 8
+        );
+    }
+}
+
+/// 内存回收函数，参数为需要的页数，返回实际释放的页数
+pub type ReclaimHook = fn(usize) -> usize;
+
+pub struct FrameAllocator {
+    regions: Vec<FrameRegion>,
+    /// next-fit 提示，(内存段下标, 页下标)
+    hint: (usize, usize),
+}
+
+/// 页帧使用情况
+#[derive(Debug, Clone, Copy)]
+pub struct FrameStats {
+    /// 总页数
+    pub total: usize,
+    /// 空闲页数
+    pub free: usize,
+}
+
+impl FrameStats {
+    /// 已使用页数
+    pub fn used(&self) -> usize {
+        self.total - self.free
+    }
 }
 
 impl FrameAllocator {
     /// 创建一个新的页帧分配器
     pub const fn new() -> Self {
         Self {
-            start: 0,
-            size: 0,
-            usage: vec![]
+            regions: vec![],
+            hint: (0, 0),
         }
     }
 
+    /// 添加一段物理内存，首尾不足一页的部分会被忽略
     pub fn add_memory(&mut self, start: usize, size: usize) {
-        self.start = start;
-        self.size = size;
-        self.usage = vec![false; size / 0x1000];
+        let end = (start + size) / PAGE_SIZE * PAGE_SIZE;
+        let start = (start + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE;
+        if end > start {
+            self.regions.push(FrameRegion::new(start, (end - start) / PAGE_SIZE));
+        }
     }
 
-    pub fn alloc(&mut self) -> TrackerFrame {
-        for i in 0..self.usage.len() {
-            if self.usage[i] == false {
-                self.usage[i] = true;
-                return TrackerFrame(self.start + i * 0x1000);
+    /// 分配一个页帧，返回物理地址
+    pub fn alloc(&mut self) -> Result<usize, FrameError> {
+        let (hint_region, hint_index) = self.hint;
+        let count = self.regions.len();
+        for i in 0..count {
+            let region_index = (hint_region + i) % count;
+            let region = &mut self.regions[region_index];
+            let hint = if i == 0 { hint_index } else { 0 };
+            if let Some(index) = region.alloc(hint) {
+                self.hint = (region_index, index + 1);
+                let paddr = region.start + index * PAGE_SIZE;
+                #[cfg(feature = "frame-poison")]
+                check_poison(paddr);
+                return Ok(paddr);
             }
         }
-        todo!()
+        Err(FrameError::OutOfMemory)
     }
 
-    pub fn dealloc(&mut self, addr: usize) {
-        let page_index = (addr - self.start) / 0x1000;
-        self.usage[page_index] = false;
+    /// 分配 count 个连续的页帧，起始地址按 align 字节对齐 (2 的幂，至少一页)
+    pub fn alloc_contiguous(
+        &mut self,
+        count: usize,
+        align: usize,
+    ) -> Result<TrackerFrames, FrameError> {
+        assert!(align.is_power_of_two(), "align must be a power of two");
+        let align = align.max(PAGE_SIZE);
+        let frames = self
+            .regions
+            .iter_mut()
+            .find_map(|region| {
+                region
+                    .alloc_contiguous(count, align)
+                    .map(|index| TrackerFrames {
+                        start: region.start + index * PAGE_SIZE,
+                        count,
+                    })
+            })
+            .ok_or(FrameError::OutOfMemory)?;
+        #[cfg(feature = "frame-poison")]
+        (0..count).for_each(|i| check_poison(frames.start + i * PAGE_SIZE));
+        Ok(frames)
+    }
+
+    /// 释放页帧，检查地址是否对齐、是否属于分配器以及是否重复释放
+    pub fn dealloc(&mut self, addr: usize) -> Result<(), FrameError> {
+        if addr % PAGE_SIZE != 0 {
+            return Err(FrameError::NotAligned);
+        }
+        let region = self
+            .regions
+            .iter_mut()
+            .find(|region| region.contains(addr))
+            .ok_or(FrameError::InvalidAddress)?;
+        let page_index = (addr - region.start) / PAGE_SIZE;
+        if !region.is_used(page_index) {
+            return Err(FrameError::DoubleFree);
+        }
+        region.set_used(page_index, false);
+        #[cfg(feature = "frame-poison")]
+        poison_frame(addr);
+        Ok(())
+    }
+
+    /// 释放页帧，出错时输出错误信息
+    fn dealloc_or_report(&mut self, addr: usize) {
+        if let Err(err) = self.dealloc(addr) {
+            error!("frame allocator: failed to free frame {:#x}: {}", addr, err);
+        }
+    }
+
+    /// 页帧使用情况
+    pub fn stats(&self) -> FrameStats {
+        FrameStats {
+            total: self.regions.iter().map(|region| region.pages).sum(),
+            free: self.regions.iter().map(|region| region.free).sum(),
+        }
     }
 }
 
-pub struct TrackerFrame(pub usize);
+/// 页帧的所有权，离开作用域时释放页帧
+struct FrameOwner(usize);
 
-impl Drop for TrackerFrame {
+impl Drop for FrameOwner {
     fn drop(&mut self) {
-        FRAME_ALLOCATOR.lock().dealloc(self.0);
+        with_allocator(|allocator| allocator.dealloc_or_report(self.0));
+    }
+}
+
+/// 引用计数的页帧，可以被多个地址空间共享，最后一个引用离开作用域时自动释放
+#[derive(Clone)]
+pub struct TrackerFrame(Arc<FrameOwner>);
+
+impl TrackerFrame {
+    /// 物理地址
+    pub fn paddr(&self) -> usize {
+        self.0 .0
+    }
+
+    /// 是否还有其他引用
+    pub fn is_shared(&self) -> bool {
+        Arc::strong_count(&self.0) > 1
+    }
+
+    /// 以字节数组的形式访问页帧
+    pub fn as_bytes_mut(&self) -> &'static mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(paddr_to_virt(self.paddr()) as *mut u8, PAGE_SIZE) }
+    }
+}
+
+/// 连续的多个页帧，离开作用域时自动释放
+pub struct TrackerFrames {
+    /// 起始物理地址
+    pub start: usize,
+    /// 页数
+    pub count: usize,
+}
+
+impl TrackerFrames {
+    /// 以字节数组的形式访问页帧
+    pub fn as_bytes_mut(&self) -> &'static mut [u8] {
+        unsafe {
+            core::slice::from_raw_parts_mut(
+                paddr_to_virt(self.start) as *mut u8,
+                self.count * PAGE_SIZE,
+            )
+        }
+    }
+}
+
+impl Drop for TrackerFrames {
+    fn drop(&mut self) {
+        with_allocator(|allocator| {
+            (0..self.count).for_each(|i| allocator.dealloc_or_report(self.start + i * PAGE_SIZE));
+        });
     }
 }
 
 static FRAME_ALLOCATOR: Mutex<FrameAllocator> = Mutex::new(FrameAllocator::new());
 
+/// 持有 FRAME_ALLOCATOR 的锁执行 f
+///
+/// 持有锁时关闭中断，否则被抢占后，更高优先级的任务在这个锁上空转，持有锁的任务再也得不到运行
+fn with_allocator<T>(f: impl FnOnce(&mut FrameAllocator) -> T) -> T {
+    without_interrupts(|| f(&mut FRAME_ALLOCATOR.lock()))
+}
+
+/// 内存不足时调用的回收函数
+static RECLAIM_HOOK: Mutex<Option<ReclaimHook>> = Mutex::new(None);
+
+/// 设置内存回收函数，分配失败时会先调用它释放内存再重试一次
+#[allow(dead_code)]
+pub fn set_reclaim_hook(hook: ReclaimHook) {
+    without_interrupts(|| *RECLAIM_HOOK.lock() = Some(hook));
+}
+
+/// 分配页帧，内存不足时调用回收函数后重试
+///
+/// 调用回收函数时不持有 FRAME_ALLOCATOR 的锁，回收函数中可以释放页帧
+fn alloc_or_reclaim<T>(
+    pages: usize,
+    alloc: impl Fn(&mut FrameAllocator) -> Result<T, FrameError>,
+) -> Result<T, FrameError> {
+    let result = with_allocator(&alloc);
+    if result.is_ok() {
+        return result;
+    }
+    let hook = without_interrupts(|| *RECLAIM_HOOK.lock());
+    let result = match hook {
+        Some(hook) if hook(pages) > 0 => with_allocator(&alloc),
+        _ => result,
+    };
+    if result.is_err() {
+        warn!("frame allocator: failed to allocate {} page(s)", pages);
+    }
+    result
+}
+
+/// 分配一个清零的页帧
+///
+/// 引用计数在释放 FRAME_ALLOCATOR 的锁之后分配，分配时内核堆可能需要扩展
+pub fn frame_alloc() -> Result<TrackerFrame, FrameError> {
+    let paddr = alloc_or_reclaim(1, |allocator| allocator.alloc())?;
+    let frame = TrackerFrame(Arc::new(FrameOwner(paddr)));
+    frame.as_bytes_mut().fill(0);
+    Ok(frame)
+}
+
+/// 分配 count 个清零的连续页帧，起始地址按 align 字节对齐
+pub fn frame_alloc_contiguous(count: usize, align: usize) -> Result<TrackerFrames, FrameError> {
+    let frames = alloc_or_reclaim(count, |allocator| allocator.alloc_contiguous(count, align))?;
+    frames.as_bytes_mut().fill(0);
+    Ok(frames)
+}
+
+/// 内核堆每次扩展的最小大小
+const HEAP_GROW_MIN: usize = 0x4_0000;
+
+/// 为内核堆分配内存，返回内存的虚拟地址和大小，分配的页帧不会再被释放
+///
+/// 内存按大小对齐，伙伴分配器才能把整块内存用于一次分配。
+/// 堆扩展发生在内存分配过程中，此时可能正持有 FRAME_ALLOCATOR 的锁
+/// (如 add_memory 中分配位图)，所以只尝试加锁，失败时直接返回 None。
+pub fn heap_grow(size: usize) -> Option<(usize, usize)> {
+    let size = size.next_power_of_two().max(HEAP_GROW_MIN);
+    let frames = without_interrupts(|| {
+        FRAME_ALLOCATOR
+            .try_lock()?
+            .alloc_contiguous(size / PAGE_SIZE, size)
+            .ok()
+    })?;
+    let start = paddr_to_virt(frames.start);
+    core::mem::forget(frames);
+    debug!("kernel heap grows {:#x} bytes at {:#x}", size, start);
+    Some((start, size))
+}
+
+/// 页帧使用情况
+pub fn frame_stats() -> FrameStats {
+    with_allocator(|allocator| allocator.stats())
+}
+
 pub fn add_frame_area(start: usize, size: usize) {
     info!("add frame area {:#x} - {:#x} to frame alloctor", start, start + size);
+    // 开启 frame-poison 时填充 POISON，第一次分配时的检查才不会误报
+    #[cfg(not(feature = "frame-poison"))]
+    unsafe {
+        core::slice::from_raw_parts_mut(paddr_to_virt(start) as *mut u128, size / 16).fill(0);
+    }
+    #[cfg(feature = "frame-poison")]
     unsafe {
-        core::slice::from_raw_parts_mut(start as *mut u128, size / 16).fill(0);
+        core::slice::from_raw_parts_mut(paddr_to_virt(start) as *mut u64, size / 8).fill(POISON);
     }
-    FRAME_ALLOCATOR.lock().add_memory(start, size);
+    with_allocator(|allocator| allocator.add_memory(start, size));
     // test frame allocation and test auto drop
     // let mut arr = vec![];
     // for _ in 0..20000 {
@@ -0,0 +1,183 @@
+//! 内核调试 shell
+//!
+//! 从控制台终端读取命令并执行，命令按空白分隔为名称和参数。
+//! 其他模块可以通过 register 添加命令。
+
+use alloc::{string::String, vec::Vec};
+use core::time::Duration;
+
+use log::LevelFilter;
+use spin::Mutex;
+use timestamp::DateTime;
+
+use crate::frame::frame_stats;
+use crate::page_table::PAGE_SIZE;
+use crate::rtc::NANOS_PER_SEC;
+use crate::sbi::{self, EXTENSION_SRST, RESET_REASON_NO_REASON, RESET_TYPE_COLD_REBOOT};
+use crate::{clock, logging, print, println, rtc, tty, uart};
+
+/// 命令处理函数，参数为命令名之后的各个参数
+pub type CommandHandler = fn(&[&str]);
+
+struct Command {
+    name: &'static str,
+    help: &'static str,
+    handler: CommandHandler,
+}
+
+static COMMANDS: Mutex<Vec<Command>> = Mutex::new(Vec::new());
+
+/// 注册命令，同名的命令会被替换
+pub fn register(name: &'static str, help: &'static str, handler: CommandHandler) {
+    let mut commands = COMMANDS.lock();
+    commands.retain(|command| command.name != name);
+    commands.push(Command { name, help, handler });
+    commands.sort_unstable_by_key(|command| command.name);
+}
+
+/// 注册内置命令
+pub fn init() {
+    register("help", "list commands", help);
+    register("mem", "show frame allocator usage", mem);
+    register("heap", "show kernel heap usage", heap);
+    register("fdt", "list device tree nodes", fdt);
+    register("date", "show or set the wall-clock time: date [seconds since 1970]", date);
+    register("alarm", "ring after some seconds: alarm <seconds>|cancel", alarm);
+    register("loglevel", "show or set the log level: loglevel [off|error|warn|info|debug|trace]", loglevel);
+    register("reboot", "reboot the machine", reboot);
+    register("shutdown", "power off the machine", shutdown);
+}
+
+/// 运行 shell，输入 exit 时返回
+pub fn run() {
+    println!("kernel shell, type `help` for commands, `exit` to leave");
+    loop {
+        print!("kshell> ");
+        // Ctrl-C 放弃当前输入
+        let Some(line) = tty::read_line() else {
+            continue;
+        };
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let Some((name, args)) = words.split_first() else {
+            continue;
+        };
+        if *name == "exit" {
+            return;
+        }
+        let handler = COMMANDS
+            .lock()
+            .iter()
+            .find(|command| command.name == *name)
+            .map(|command| command.handler);
+        match handler {
+            Some(handler) => handler(args),
+            None => println!("{}: command not found", name),
+        }
+    }
+}
+
+fn help(_args: &[&str]) {
+    for command in COMMANDS.lock().iter() {
+        println!("{:<10} {}", command.name, command.help);
+    }
+    println!("{:<10} {}", "exit", "leave the shell");
+}
+
+fn mem(_args: &[&str]) {
+    let stats = frame_stats();
+    println!(
+        "frames: {} total, {} used, {} free ({} KiB free)",
+        stats.total,
+        stats.used(),
+        stats.free,
+        stats.free * PAGE_SIZE / 1024
+    );
+}
+
+fn heap(_args: &[&str]) {
+    let stats = allocator::stats();
+    println!(
+        "heap: {} bytes total, {} used, {} peak, {} allocs, {} frees",
+        stats.total_bytes, stats.used_bytes, stats.peak_bytes, stats.alloc_count, stats.free_count
+    );
+    allocator::dump_slab_stats();
+}
+
+fn fdt(_args: &[&str]) {
+    for node in crate::device_tree().all_nodes() {
+        let compatible = node
+            .compatible()
+            .map(|compatible| compatible.all().intersperse(" ").collect::<String>())
+            .unwrap_or_default();
+        println!("{:<32} {}", node.name, compatible);
+    }
+}
+
+fn date(args: &[&str]) {
+    if let Some(seconds) = args.first() {
+        let valid = seconds
+            .parse::<u64>()
+            .is_ok_and(|seconds| clock::set_realtime(Duration::from_secs(seconds)));
+        if !valid {
+            println!("invalid time {}", seconds);
+            return;
+        }
+    }
+    let dt = DateTime::new(clock::realtime().as_secs() as usize);
+    println!("{} ({})", dt, dt.timestamp);
+    if let Some(rtc) = rtc::wall_clock() {
+        println!("rtc: {}", rtc);
+    }
+}
+
+fn alarm(args: &[&str]) {
+    match args.first().copied() {
+        Some("cancel") => rtc::cancel_alarm(),
+        Some(seconds) => {
+            let Some(now) = rtc::time_nanos() else {
+                println!("no real-time clock");
+                return;
+            };
+            let Some(time) = seconds
+                .parse::<u64>()
+                .ok()
+                .and_then(|seconds| seconds.checked_mul(NANOS_PER_SEC))
+                .and_then(|nanos| now.checked_add(nanos))
+            else {
+                println!("invalid time {}", seconds);
+                return;
+            };
+            let ring = || println!("\x07alarm!");
+            if !rtc::set_alarm(time, ring) {
+                println!("rtc alarm interrupt is not available");
+            }
+        }
+        None => println!("usage: alarm <seconds>|cancel"),
+    }
+}
+
+fn loglevel(args: &[&str]) {
+    let Some(level) = args.first() else {
+        println!("{}", log::max_level());
+        return;
+    };
+    match logging::parse_level(level) {
+        Some(level) => log::set_max_level(level),
+        None => println!("unknown log level {}, default is {}", level, LevelFilter::Info),
+    }
+}
+
+fn reboot(_args: &[&str]) {
+    if !sbi::probe_extension(EXTENSION_SRST) {
+        println!("reboot is not supported by the sbi implementation");
+        return;
+    }
+    uart::flush();
+    let ret = sbi::system_reset(RESET_TYPE_COLD_REBOOT, RESET_REASON_NO_REASON);
+    println!("reboot failed: sbi error {}", ret.error as isize);
+}
+
+fn shutdown(_args: &[&str]) {
+    uart::flush();
+    sbi::shutdown()
+}
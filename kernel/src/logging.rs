@@ -31,18 +31,24 @@ impl Log for Logger {
     fn flush(&self) {}
 }
 
+/// 解析日志级别名称
+pub fn parse_level(level: &str) -> Option<LevelFilter> {
+    match level {
+        "off" => Some(LevelFilter::Off),
+        "error" => Some(LevelFilter::Error),
+        "warn" => Some(LevelFilter::Warn),
+        "info" => Some(LevelFilter::Info),
+        "debug" => Some(LevelFilter::Debug),
+        "trace" => Some(LevelFilter::Trace),
+        _ => None,
+    }
+}
+
 /// LOG level
 /// Trace < Debug < Info < Warn < Error
 pub fn init(level: Option<&str>) {
     log::set_logger(&Logger).unwrap();
-    log::set_max_level(match level {
-        Some("error") => LevelFilter::Error,
-        Some("warn") => LevelFilter::Warn,
-        Some("info") => LevelFilter::Info,
-        Some("debug") => LevelFilter::Debug,
-        Some("trace") => LevelFilter::Trace,
-        _ => LevelFilter::Info,
-    });
+    log::set_max_level(level.and_then(parse_level).unwrap_or(LevelFilter::Info));
     info!("logging module initialized");
 }
 
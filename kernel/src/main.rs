@@ -5,9 +5,24 @@
 #![feature(asm_const)]
 #![feature(iter_intersperse)]
 
+mod clock;
 mod frame;
+mod kshell;
 mod logging;
+mod memory_map;
+mod memory_set;
+mod page_table;
+mod plic;
+mod process;
+mod ring_buffer;
+mod rtc;
 mod sbi;
+mod syscall;
+mod task;
+mod timer;
+mod trap;
+mod tty;
+mod uart;
 
 #[macro_use]
 extern crate alloc;
@@ -15,15 +30,20 @@ extern crate allocator;
 
 use alloc::string::String;
 use log::{debug, error, info, trace, warn};
-use timestamp::DateTime;
 use core::{
     fmt::{self, Write},
-    panic::PanicInfo, ptr::read_volatile,
+    mem,
+    panic::PanicInfo,
+    sync::atomic::{AtomicUsize, Ordering},
 };
 use fdt::Fdt;
 use sbi::{console_putchar, shutdown};
 
-use crate::frame::add_frame_area;
+use crate::frame::{add_frame_area, frame_stats};
+use crate::memory_map::MemoryMap;
+use crate::page_table::{
+    paddr_to_virt, vpn_index, PTEFlags, PageTableEntry, PAGE_SIZE, VIRT_ADDR_START,
+};
 
 /// RISCV boot: OpenSBI -> OS, a0: hart_id, a1: device_tree
 
@@ -40,9 +60,34 @@ const STACK_SIZE: usize = 0x8_0000; // 1k * 16 * 8, 128k
 #[link_section = ".bss.stack"]
 static mut STACK: [u8; STACK_SIZE] = [0u8; STACK_SIZE];
 
+/// 启动页表
+///
+/// 0x8000_0000 处 1G 恒等映射，保证开启分页后 _start 还能继续执行，
+/// VIRT_ADDR_START 开始的 4G 映射到物理地址 0 - 4G，内核在这里运行。
+#[link_section = ".data.prepage"]
+static mut BOOT_PAGE_TABLE: [PageTableEntry; 512] = {
+    let flags = PTEFlags::V
+        .union(PTEFlags::R)
+        .union(PTEFlags::W)
+        .union(PTEFlags::X)
+        .union(PTEFlags::A)
+        .union(PTEFlags::D);
+    let mut table = [PageTableEntry::empty(); 512];
+    table[vpn_index(0x8000_0000, 2)] = PageTableEntry::new(0x8000_0000, flags);
+    let mut i = 0;
+    while i < 4 {
+        table[vpn_index(VIRT_ADDR_START, 2) + i] = PageTableEntry::new(i * 0x4000_0000, flags);
+        i += 1;
+    }
+    table
+};
+
 /// 汇编入口函数
 ///
 /// 分配栈 初始化页表信息 并调到rust入口函数
+///
+/// 此时还运行在物理地址上，la 得到的都是物理地址，
+/// 开启启动页表后把 sp 和 main 的地址加上 VIRT_ADDR_START 跳到高地址运行。
 #[naked]
 #[no_mangle]
 #[link_section = ".text.entry"]
@@ -53,16 +98,44 @@ unsafe extern "C" fn _start() -> ! {
             la      sp, {boot_stack}
             li      t0, {stack_size}
             add     sp, sp, t0              // set boot stack
-            call    main
+        ",
+        // 2. 开启启动页表
+        "
+            la      t0, {boot_page_table}
+            srli    t0, t0, 12
+            li      t1, 8 << 60
+            or      t0, t0, t1
+            csrw    satp, t0
+            sfence.vma
+        ",
+        // 3. 跳转到高地址
+        "
+            li      t0, {virt_addr_start}
+            add     sp, sp, t0
+            la      t1, main
+            add     t1, t1, t0
+            jr      t1
         ",
         stack_size = const STACK_SIZE,
         boot_stack = sym STACK,
+        boot_page_table = sym BOOT_PAGE_TABLE,
+        virt_addr_start = const VIRT_ADDR_START as isize,
         options(noreturn),
     )
 }
 
 fn puts(display_str: &str) {
-    for c in display_str.as_bytes() {
+    put_bytes(display_str.as_bytes());
+}
+
+/// 输出字节，用户程序的输出不一定是合法的 UTF-8
+///
+/// 串口初始化之前通过 SBI 输出
+pub fn put_bytes(bytes: &[u8]) {
+    if uart::put_bytes(bytes) {
+        return;
+    }
+    for c in bytes {
         console_putchar(*c);
     }
 }
@@ -89,15 +162,26 @@ fn get_kernel_range() -> (usize, usize) {
     (_skernel as usize, _ekernel as usize)
 }
 
+/// 设备树的物理地址
+static DEVICE_TREE: AtomicUsize = AtomicUsize::new(0);
+
+/// 启动时传入的设备树
+pub fn device_tree() -> Fdt<'static> {
+    let addr = paddr_to_virt(DEVICE_TREE.load(Ordering::Relaxed));
+    unsafe { Fdt::from_ptr(addr as *const u8).expect("This is a not a valid device tree") }
+}
 
 #[no_mangle]
 fn main(hart_id: usize, device_tree: usize) -> ! {
     clear_bss();
 
     allocator::init();
+    allocator::set_irq_hooks(trap::disable_interrupts, trap::restore_interrupts);
     // env: Environment
     logging::init(option_env!("LOG"));
 
+    trap::init();
+
     puts(include_str!("banner.txt"));
 
     trace!("Hello Trace");
@@ -112,8 +196,10 @@ fn main(hart_id: usize, device_tree: usize) -> ! {
     info!("device_tree addr: {:#x}", device_tree); // 0x 十六进制， 0o 八进制， 0b 二进制
 
     let fdt = unsafe {
-        Fdt::from_ptr(device_tree as *const u8).expect("This is a not a valid device tree")
+        Fdt::from_ptr(paddr_to_virt(device_tree) as *const u8)
+            .expect("This is a not a valid device tree")
     };
+    DEVICE_TREE.store(device_tree, Ordering::Relaxed);
 
     info!(
         "Platform: {}  {} CPU(s)",
@@ -121,6 +207,18 @@ fn main(hart_id: usize, device_tree: usize) -> ! {
         fdt.cpus().count()
     );
 
+    // 开启 leak-tracking 时，启动的各个阶段分别记录堆分配，标签是对应的 leak_scope 的位置
+    {
+        let _leak_scope = allocator::leak_scope();
+        plic::init(&fdt, hart_id);
+        uart::init(&fdt);
+        rtc::init(&fdt);
+    }
+    timer::init(&fdt);
+    clock::init();
+    // 等待一次时钟中断，确认时钟正常工作
+    timer::sleep_until(timer::ticks() + 1);
+
     // 1024 1k  0x1000 4k 0x8000000 / 0x1000 = 0x8000 * 4Kb 8 * 4K * 4k = 4*4*8 = 16 * 8 = 128M
     // x86_64 段式内存管理 页式内存管理  页式 4K
     
@@ -131,37 +229,101 @@ fn main(hart_id: usize, device_tree: usize) -> ! {
                 child.name,
                 compatible.all().intersperse(" ").collect::<String>()
             );
-            if let Some(_) = compatible.all().find(|x| *x == "google,goldfish-rtc") {
-                let base_addr = child.reg().unwrap().next().unwrap().starting_address as usize;
-                let timestamp = unsafe {
-                    let low: u32 = read_volatile((base_addr + 0x0) as *const u32);
-                    let high: u32 = read_volatile((base_addr + 0x4) as *const u32);
-                    ((high as u64) << 32) | (low as u64) 
-                } / 1_000_000_000u64;
-                let dt = DateTime::new(timestamp as usize);
-                info!("dt: {:#?}", dt);
-            }
         }
     });
 
-    let mut mem_start = 0;
-    let mut mem_size = 0;
-
-    fdt.memory().regions().for_each(|x| {
-        info!(
-            "Memory region {:#x} - {:#x}",
-            x.starting_address as usize,
-            x.starting_address as usize + x.size.unwrap()
-        );
-        mem_start = get_kernel_range().1;
-        mem_size = x.size.unwrap() - (get_kernel_range().1 - 0x8000_0000);
-    });
-    
-    add_frame_area(mem_start, mem_size);
+    let memory_map = {
+        let _leak_scope = allocator::leak_scope();
+        let memory_map = MemoryMap::from_fdt(&fdt, device_tree);
+        for range in &memory_map.usable {
+            add_frame_area(range.start, range.size());
+        }
+        memory_map
+    };
+    // .bss 中的静态堆只够启动早期使用，堆的主要空间从页帧分配器中分配
+    let heap_size = allocator::HEAP_SIZE.next_power_of_two();
+    let heap = frame::frame_alloc_contiguous(heap_size / PAGE_SIZE, heap_size)
+        .expect("can't allocate kernel heap");
+    unsafe { allocator::init_with_region(paddr_to_virt(heap.start), heap_size) };
+    // 堆不会再被释放
+    mem::forget(heap);
+    allocator::set_grow_hook(frame::heap_grow);
+
+    let stats = frame_stats();
+    info!(
+        "frame allocator: {} pages total, {} pages used, {} pages free",
+        stats.total,
+        stats.used(),
+        stats.free
+    );
+
+    {
+        let _leak_scope = allocator::leak_scope();
+        page_table::init_kernel_space(memory_map.mem_end);
+    }
+    {
+        let _leak_scope = allocator::leak_scope();
+        task::init();
+    }
+
+    // 第一个用户程序，编译时通过环境变量 INIT_ELF 指定
+    if let Some(init) = process::find_program("init") {
+        let _leak_scope = allocator::leak_scope();
+        match process::spawn_user("init", init, &["init"], &[]) {
+            Ok(pid) => info!("init process started, pid {}", pid),
+            Err(err) => error!("can't start init process: {:?}", err),
+        }
+    }
+    // 等待其他内核线程和用户进程结束
+    while task::task_count() > 1 {
+        timer::sleep_until(timer::ticks() + 1);
+    }
+
+    // 用户进程结束后控制台交给内核 shell，退出 shell 后关机
+    kshell::init();
+    kshell::register("ps", "list tasks", |_| task::dump_stats());
+    kshell::register("threads", "run kernel threads to show preemption", |_| run_demo_threads());
+    kshell::run();
+    task::dump_stats();
 
+    allocator::dump_slab_stats();
+    allocator::report_leaks();
+
+    info!("uptime: {:?}", timer::uptime());
+    uart::flush();
     shutdown()
 }
 
+/// 运行三个不主动让出 CPU 的内核线程，演示时钟中断抢占和优先级，等待它们结束后返回
+fn run_demo_threads() {
+    let worker = |i: usize| {
+        move || {
+            let current = task::current().unwrap();
+            for round in 0..3 {
+                info!(
+                    "kernel thread {} ({}, id {}, {:?}) round {}",
+                    i,
+                    current.name(),
+                    current.id(),
+                    current.priority(),
+                    round
+                );
+                // 不主动让出 CPU，由时钟中断抢占
+                let end = timer::ticks() + 5;
+                while timer::ticks() < end {}
+            }
+        }
+    };
+    let count = task::task_count();
+    task::spawn("hello", worker(0)).expect("can't spawn kernel thread");
+    task::spawn("hello", worker(1)).expect("can't spawn kernel thread");
+    task::spawn_with_priority("urgent", task::Priority::High, worker(2))
+        .expect("can't spawn kernel thread");
+    while task::task_count() > count {
+        timer::sleep_until(timer::ticks() + 1);
+    }
+}
+
 struct Logger;
 
 impl Write for Logger {
@@ -176,6 +338,7 @@ impl Write for Logger {
 fn panic_handler(info: &PanicInfo) -> ! {
     // puts("");
     // Logger.write_fmt(*info.message().unwrap());
+    uart::set_polled();
     error!("An error occurred: {}", info.message().unwrap());
     shutdown()
 }
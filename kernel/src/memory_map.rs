@@ -0,0 +1,141 @@
+//! 物理内存布局
+//!
+//! 从设备树中找出所有物理内存，去掉内核镜像、设备树、/reserved-memory、
+//! memreserve 块、initrd 和固件占用的部分，剩下的交给页帧分配器。
+
+use alloc::vec::Vec;
+use fdt::Fdt;
+use log::info;
+
+use crate::page_table::{virt_to_paddr, PAGE_SIZE};
+
+/// 一段物理内存 [start, end)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryRange {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl MemoryRange {
+    pub const fn new(start: usize, end: usize) -> Self {
+        Self { start, end }
+    }
+
+    pub const fn size(&self) -> usize {
+        self.end - self.start
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.start >= self.end
+    }
+}
+
+/// 物理内存布局
+pub struct MemoryMap {
+    /// 可用的物理内存，按页对齐
+    pub usable: Vec<MemoryRange>,
+    /// 物理内存的最高地址
+    pub mem_end: usize,
+}
+
+/// 从 ranges 中去掉 reserved 部分
+fn subtract(ranges: Vec<MemoryRange>, reserved: MemoryRange) -> Vec<MemoryRange> {
+    let mut result = Vec::new();
+    for range in ranges {
+        if reserved.end <= range.start || reserved.start >= range.end {
+            result.push(range);
+            continue;
+        }
+        let left = MemoryRange::new(range.start, reserved.start);
+        let right = MemoryRange::new(reserved.end, range.end);
+        result.extend([left, right].into_iter().filter(|x| !x.is_empty()));
+    }
+    result
+}
+
+impl MemoryMap {
+    /// 根据设备树构建物理内存布局，device_tree 是设备树的物理地址
+    pub fn from_fdt(fdt: &Fdt, device_tree: usize) -> Self {
+        extern "C" {
+            fn _skernel();
+            fn _ekernel();
+        }
+
+        let memory: Vec<MemoryRange> = fdt
+            .memory()
+            .regions()
+            .filter_map(|region| {
+                let start = region.starting_address as usize;
+                Some(MemoryRange::new(start, start + region.size?))
+            })
+            .collect();
+        let mem_end = memory.iter().map(|x| x.end).max().unwrap_or(0);
+        memory
+            .iter()
+            .for_each(|x| info!("memory region {:#x} - {:#x}", x.start, x.end));
+
+        let kernel_start = virt_to_paddr(_skernel as usize);
+        let kernel_end = virt_to_paddr(_ekernel as usize);
+
+        let mut reserved = Vec::new();
+        reserved.push((MemoryRange::new(kernel_start, kernel_end), "kernel"));
+        reserved.push((
+            MemoryRange::new(device_tree, device_tree + fdt.total_size()),
+            "device tree",
+        ));
+        // 固件 (OpenSBI) 放在内核前面，旧版本的固件不会在设备树中声明这段内存
+        if let Some(x) = memory.iter().find(|x| (x.start..x.end).contains(&kernel_start)) {
+            reserved.push((MemoryRange::new(x.start, kernel_start), "firmware"));
+        }
+        for rsv in fdt.memory_reservations() {
+            let start = rsv.address() as usize;
+            reserved.push((MemoryRange::new(start, start + rsv.size()), "memreserve"));
+        }
+        if let Some(node) = fdt.find_node("/reserved-memory") {
+            for child in node.children() {
+                child.reg().into_iter().flatten().for_each(|reg| {
+                    let start = reg.starting_address as usize;
+                    let end = start + reg.size.unwrap_or(0);
+                    reserved.push((MemoryRange::new(start, end), child.name));
+                });
+            }
+        }
+        let chosen = fdt.find_node("/chosen");
+        let initrd_start = chosen
+            .and_then(|x| x.property("linux,initrd-start"))
+            .and_then(|x| x.as_usize());
+        let initrd_end = chosen
+            .and_then(|x| x.property("linux,initrd-end"))
+            .and_then(|x| x.as_usize());
+        if let (Some(start), Some(end)) = (initrd_start, initrd_end) {
+            reserved.push((MemoryRange::new(start, end), "initrd"));
+        }
+
+        let mut usable = memory;
+        for (range, name) in reserved {
+            if range.is_empty() {
+                continue;
+            }
+            info!("reserved {:<20} {:#x} - {:#x}", name, range.start, range.end);
+            // 保留区域向外扩展到页边界
+            let range = MemoryRange::new(
+                range.start / PAGE_SIZE * PAGE_SIZE,
+                (range.end + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE,
+            );
+            usable = subtract(usable, range);
+        }
+        let mut usable: Vec<MemoryRange> = usable
+            .into_iter()
+            .map(|x| {
+                MemoryRange::new(
+                    (x.start + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE,
+                    x.end / PAGE_SIZE * PAGE_SIZE,
+                )
+            })
+            .filter(|x| !x.is_empty())
+            .collect();
+        usable.sort_by_key(|x| x.start);
+
+        Self { usable, mem_end }
+    }
+}
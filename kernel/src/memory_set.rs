@@ -0,0 +1,515 @@
+//! 用户地址空间
+//!
+//! 每个用户进程有一个地址空间，由页表和若干段不重叠的映射区域 (VMA) 组成。
+//! 区域记录地址范围、权限、后备 (匿名内存、文件或设备) 以及是否共享，
+//! 按起始地址保存在 BTreeMap 中，缺页时可以快速找到地址所在的区域。
+//! 高地址的内核部分和内核页表共享。
+//!
+//! 匿名和文件映射的页帧在第一次访问触发缺页时才分配，匿名页清零，文件页从文件复制。
+//! fork 时父子进程共享页帧，私有可写的页在两边都映射为只读，写入时触发缺页再复制 (copy-on-write)，
+//! 共享映射和设备映射在两边都保持原来的权限。
+//! mprotect 和 munmap 在边界处拆分区域，之后相邻并且属性相同的区域会被合并。
+
+use alloc::{
+    collections::{btree_map::Entry, BTreeMap},
+    vec::Vec,
+};
+use core::mem;
+
+use crate::frame::{frame_alloc, TrackerFrame};
+use crate::page_table::{paddr_to_virt, PTEFlags, PageSize, PageTable, PagingError, PAGE_SIZE};
+
+/// 访问内存的方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessType {
+    Read,
+    Write,
+    Execute,
+}
+
+impl AccessType {
+    /// 需要的页表项权限
+    fn flags(self) -> PTEFlags {
+        match self {
+            AccessType::Read => PTEFlags::R,
+            AccessType::Write => PTEFlags::W,
+            AccessType::Execute => PTEFlags::X,
+        }
+    }
+}
+
+/// 区域的后备
+#[derive(Debug, Clone, Copy)]
+pub enum Backing {
+    /// 匿名内存，页帧清零
+    Anonymous,
+    /// 文件内容，data 从区域的起始地址开始，超出 data 的部分为 0
+    File { data: &'static [u8] },
+    /// 设备内存，区域的起始地址对应物理地址 paddr，映射时就建立所有页表项
+    #[allow(dead_code)]
+    Device { paddr: usize },
+}
+
+impl Backing {
+    /// 从区域中 offset 处开始的部分的后备
+    fn offset(self, offset: usize) -> Self {
+        match self {
+            Backing::Anonymous => Backing::Anonymous,
+            Backing::File { data } => Backing::File {
+                data: data.get(offset..).unwrap_or(&[]),
+            },
+            Backing::Device { paddr } => Backing::Device {
+                paddr: paddr + offset,
+            },
+        }
+    }
+
+    /// 为区域中 offset 处的页分配页帧，文件映射从文件中复制内容
+    fn alloc_frame(self, offset: usize) -> Result<TrackerFrame, PagingError> {
+        let frame = frame_alloc()?;
+        if let Backing::File { data } = self {
+            if let Some(src) = data.get(offset..) {
+                let len = src.len().min(PAGE_SIZE);
+                frame.as_bytes_mut()[..len].copy_from_slice(&src[..len]);
+            }
+        }
+        Ok(frame)
+    }
+}
+
+/// 一段映射区域 [start, end)
+pub struct MapArea {
+    pub start: usize,
+    pub end: usize,
+    pub flags: PTEFlags,
+    pub backing: Backing,
+    /// 共享映射，fork 之后父子进程仍然共享页帧
+    pub shared: bool,
+    /// 虚拟页起始地址到页帧的映射，还没有访问过的页和设备映射的页不在这里
+    frames: BTreeMap<usize, TrackerFrame>,
+}
+
+impl MapArea {
+    pub fn contains(&self, vaddr: usize) -> bool {
+        (self.start..self.end).contains(&vaddr)
+    }
+
+    /// 在 addr 处拆分，返回 [addr, end) 部分
+    fn split_off(&mut self, addr: usize) -> MapArea {
+        let right = MapArea {
+            start: addr,
+            end: self.end,
+            flags: self.flags,
+            backing: self.backing.offset(addr - self.start),
+            shared: self.shared,
+            frames: self.frames.split_off(&addr),
+        };
+        self.end = addr;
+        right
+    }
+
+    /// next 紧接在后面并且属性相同，可以合并成一个区域
+    fn can_merge(&self, next: &MapArea) -> bool {
+        if self.end != next.start || self.flags != next.flags || self.shared != next.shared {
+            return false;
+        }
+        let len = self.end - self.start;
+        match (self.backing, next.backing) {
+            (Backing::Anonymous, Backing::Anonymous) => true,
+            (Backing::File { data }, Backing::File { data: next_data }) => {
+                data.len() == len && data.as_ptr_range().end == next_data.as_ptr()
+            }
+            (Backing::Device { paddr }, Backing::Device { paddr: next_paddr }) => paddr + len == next_paddr,
+            _ => false,
+        }
+    }
+
+    /// 页帧的页表项权限，私有区域中还和其他地址空间共享的页帧不可写
+    fn pte_flags(&self, frame: &TrackerFrame) -> PTEFlags {
+        if !self.shared && frame.is_shared() {
+            self.flags - PTEFlags::W
+        } else {
+            self.flags
+        }
+    }
+}
+
+/// 把 vaddr 映射到 paddr 或者修改已有映射的权限
+///
+/// 没有读写执行权限的页不能放在页表中 (会被当作下一级页表)，这时取消映射，页帧仍然保留在区域中
+fn set_pte(page_table: &mut PageTable, vaddr: usize, paddr: usize, flags: PTEFlags) -> Result<(), PagingError> {
+    let mapped = page_table.translate(vaddr).is_some();
+    match (mapped, flags.intersects(PTEFlags::R | PTEFlags::W | PTEFlags::X)) {
+        (true, true) => page_table.protect(vaddr, flags),
+        (false, true) => page_table.map(vaddr, paddr, PageSize::Size4K, flags),
+        (true, false) => page_table.unmap(vaddr).map(|_| ()),
+        (false, false) => Ok(()),
+    }
+}
+
+/// 地址空间
+pub struct MemorySet {
+    page_table: PageTable,
+    /// 起始地址到区域的映射
+    areas: BTreeMap<usize, MapArea>,
+    /// 堆 (brk) 的起始地址
+    pub heap_start: usize,
+    /// 当前的 program break
+    pub brk: usize,
+}
+
+impl MemorySet {
+    /// 创建一个只有内核部分的用户地址空间
+    pub fn new_user() -> Result<Self, PagingError> {
+        Ok(Self {
+            page_table: PageTable::new_user()?,
+            areas: BTreeMap::new(),
+            heap_start: 0,
+            brk: 0,
+        })
+    }
+
+    /// 复制一个相同的地址空间 (fork)，页帧由两个地址空间共享
+    ///
+    /// 私有可写区域中的页在两边都改为只读，写入时由 handle_page_fault 复制
+    pub fn fork(&mut self) -> Result<Self, PagingError> {
+        let mut memory_set = Self::new_user()?;
+        memory_set.heap_start = self.heap_start;
+        memory_set.brk = self.brk;
+        for area in self.areas.values() {
+            if let Backing::Device { paddr } = area.backing {
+                for vaddr in (area.start..area.end).step_by(PAGE_SIZE) {
+                    set_pte(&mut memory_set.page_table, vaddr, paddr + vaddr - area.start, area.flags)?;
+                }
+            }
+            let child = MapArea {
+                start: area.start,
+                end: area.end,
+                flags: area.flags,
+                backing: area.backing,
+                shared: area.shared,
+                frames: area.frames.clone(),
+            };
+            for (vaddr, frame) in &area.frames {
+                let flags = area.pte_flags(frame);
+                if flags != area.flags {
+                    set_pte(&mut self.page_table, *vaddr, frame.paddr(), flags)?;
+                }
+                set_pte(&mut memory_set.page_table, *vaddr, frame.paddr(), flags)?;
+            }
+            memory_set.areas.insert(child.start, child);
+        }
+        Ok(memory_set)
+    }
+
+    /// 对应的 satp 值
+    pub fn satp(&self) -> usize {
+        self.page_table.satp()
+    }
+
+    /// 包含 vaddr 的区域
+    pub fn find_area(&self, vaddr: usize) -> Option<&MapArea> {
+        self.areas
+            .range(..=vaddr)
+            .next_back()
+            .map(|(_, area)| area)
+            .filter(|area| area.contains(vaddr))
+    }
+
+    fn find_area_mut(&mut self, vaddr: usize) -> Option<&mut MapArea> {
+        self.areas
+            .range_mut(..=vaddr)
+            .next_back()
+            .map(|(_, area)| area)
+            .filter(|area| area.contains(vaddr))
+    }
+
+    /// [start, end) 是否和已有的区域重叠
+    pub fn overlaps(&self, start: usize, end: usize) -> bool {
+        self.areas
+            .range(..end)
+            .next_back()
+            .map_or(false, |(_, area)| area.end > start)
+    }
+
+    /// 在 [start, end) 中查找一段长度为 len 的空闲地址，返回起始地址
+    pub fn find_free(&self, start: usize, end: usize, len: usize) -> Option<usize> {
+        let mut current = start;
+        for area in self.areas.values() {
+            if area.end <= current {
+                continue;
+            }
+            if area.start >= current.saturating_add(len) {
+                break;
+            }
+            current = area.end;
+        }
+        (current.saturating_add(len) <= end).then_some(current)
+    }
+
+    /// 映射 [start, end)，匿名和文件映射的页帧在第一次访问时分配
+    ///
+    /// 共享映射立即分配所有页帧，fork 之后父子进程才能看到同样的页
+    pub fn map(
+        &mut self,
+        start: usize,
+        end: usize,
+        flags: PTEFlags,
+        backing: Backing,
+        shared: bool,
+    ) -> Result<(), PagingError> {
+        if start % PAGE_SIZE != 0 || end % PAGE_SIZE != 0 {
+            return Err(PagingError::NotAligned);
+        }
+        if self.overlaps(start, end) {
+            return Err(PagingError::AlreadyMapped);
+        }
+        self.areas.insert(
+            start,
+            MapArea {
+                start,
+                end,
+                flags,
+                backing,
+                shared,
+                frames: BTreeMap::new(),
+            },
+        );
+        let result = match backing {
+            Backing::Device { paddr } => (start..end)
+                .step_by(PAGE_SIZE)
+                .try_for_each(|vaddr| set_pte(&mut self.page_table, vaddr, paddr + vaddr - start, flags)),
+            _ if shared => self.populate(start, end),
+            _ => Ok(()),
+        };
+        if let Err(err) = result {
+            let area = self.areas.remove(&start).unwrap();
+            self.unmap_area(area);
+            return Err(err);
+        }
+        self.merge();
+        Ok(())
+    }
+
+    /// 映射 [start, end) 的匿名内存，每页立即分配一个清零的页帧
+    pub fn map_framed(&mut self, start: usize, end: usize, flags: PTEFlags) -> Result<(), PagingError> {
+        self.map(start, end, flags, Backing::Anonymous, false)?;
+        self.populate(start, end).map_err(|err| {
+            // unmap 只在地址不对齐时失败，map 已经检查过
+            let _ = self.unmap(start, end);
+            err
+        })
+    }
+
+    /// 为 [start, end) 中还没有分配的页分配页帧
+    fn populate(&mut self, start: usize, end: usize) -> Result<(), PagingError> {
+        for area in self.areas.range_mut(..end).map(|(_, area)| area) {
+            if area.end <= start || matches!(area.backing, Backing::Device { .. }) {
+                continue;
+            }
+            for vaddr in (area.start.max(start)..area.end.min(end)).step_by(PAGE_SIZE) {
+                if let Entry::Vacant(entry) = area.frames.entry(vaddr) {
+                    let frame = area.backing.alloc_frame(vaddr - area.start)?;
+                    set_pte(&mut self.page_table, vaddr, frame.paddr(), area.flags)?;
+                    entry.insert(frame);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// 如果 addr 在某个区域的中间，在 addr 处把它拆分成两个区域
+    fn split_at(&mut self, addr: usize) {
+        if let Some(area) = self.find_area_mut(addr) {
+            if area.start != addr {
+                let right = area.split_off(addr);
+                self.areas.insert(addr, right);
+            }
+        }
+    }
+
+    /// 合并相邻并且属性相同的区域
+    fn merge(&mut self) {
+        let mut areas = mem::take(&mut self.areas).into_values();
+        let Some(mut current) = areas.next() else {
+            return;
+        };
+        for area in areas {
+            if current.can_merge(&area) {
+                current.end = area.end;
+                current.frames.extend(area.frames);
+            } else {
+                self.areas.insert(current.start, mem::replace(&mut current, area));
+            }
+        }
+        self.areas.insert(current.start, current);
+    }
+
+    /// 取消区域的所有映射，页帧随后被释放
+    fn unmap_area(&mut self, area: MapArea) {
+        let pages: Vec<usize> = match area.backing {
+            Backing::Device { .. } => (area.start..area.end).step_by(PAGE_SIZE).collect(),
+            _ => area.frames.keys().copied().collect(),
+        };
+        for vaddr in pages {
+            // 没有读写执行权限的页不在页表中
+            let _ = self.page_table.unmap(vaddr);
+        }
+    }
+
+    /// 取消 [start, end) 中的映射，部分重叠的区域会被截断或拆分
+    pub fn unmap(&mut self, start: usize, end: usize) -> Result<(), PagingError> {
+        if start % PAGE_SIZE != 0 || end % PAGE_SIZE != 0 {
+            return Err(PagingError::NotAligned);
+        }
+        self.split_at(start);
+        self.split_at(end);
+        let starts: Vec<usize> = self.areas.range(start..end).map(|(start, _)| *start).collect();
+        for start in starts {
+            let area = self.areas.remove(&start).unwrap();
+            self.unmap_area(area);
+        }
+        Ok(())
+    }
+
+    /// 修改 [start, end) 的权限 (mprotect)，部分重叠的区域会被拆分
+    ///
+    /// 范围中有没有映射的地址时返回 NotMapped，不修改任何区域
+    pub fn protect(&mut self, start: usize, end: usize, flags: PTEFlags) -> Result<(), PagingError> {
+        if start % PAGE_SIZE != 0 || end % PAGE_SIZE != 0 {
+            return Err(PagingError::NotAligned);
+        }
+        let mut current = start;
+        while current < end {
+            current = self.find_area(current).ok_or(PagingError::NotMapped)?.end;
+        }
+        self.split_at(start);
+        self.split_at(end);
+        for area in self.areas.range_mut(start..end).map(|(_, area)| area) {
+            area.flags = flags;
+            if let Backing::Device { paddr } = area.backing {
+                for vaddr in (area.start..area.end).step_by(PAGE_SIZE) {
+                    set_pte(&mut self.page_table, vaddr, paddr + vaddr - area.start, flags)?;
+                }
+            }
+            for (vaddr, frame) in &area.frames {
+                set_pte(&mut self.page_table, *vaddr, frame.paddr(), area.pte_flags(frame))?;
+            }
+        }
+        self.merge();
+        Ok(())
+    }
+
+    /// 处理 vaddr 处的缺页，分配还没有访问过的页，或者复制共享的页
+    ///
+    /// vaddr 不在任何区域中或者区域没有 access 权限时返回错误
+    pub fn handle_page_fault(&mut self, vaddr: usize, access: AccessType) -> Result<(), PagingError> {
+        let page = vaddr / PAGE_SIZE * PAGE_SIZE;
+        let area = self
+            .areas
+            .range_mut(..=vaddr)
+            .next_back()
+            .map(|(_, area)| area)
+            .filter(|area| area.contains(vaddr))
+            .ok_or(PagingError::NotMapped)?;
+        if !area.flags.contains(access.flags()) {
+            return Err(PagingError::NoPermission);
+        }
+        if let Backing::Device { paddr } = area.backing {
+            // 设备映射的页表项在映射时就已经建立，这里是 TLB 中还有旧的页表项
+            return set_pte(&mut self.page_table, page, paddr + page - area.start, area.flags);
+        }
+        match area.frames.get(&page) {
+            None => {
+                let frame = area.backing.alloc_frame(page - area.start)?;
+                area.frames.insert(page, frame);
+            }
+            Some(frame) if access == AccessType::Write && !area.shared && frame.is_shared() => {
+                let copy = frame_alloc()?;
+                copy.as_bytes_mut().copy_from_slice(frame.as_bytes_mut());
+                self.page_table.unmap(page)?;
+                area.frames.insert(page, copy);
+            }
+            // 其他地址空间已经不再共享这一页，恢复原来的权限。
+            // 权限没有变化时是 TLB 中还有旧的页表项，set_pte 会刷新它
+            Some(_) => {}
+        }
+        let frame = &area.frames[&page];
+        set_pte(&mut self.page_table, page, frame.paddr(), area.pte_flags(frame))
+    }
+
+    /// 确保 [vaddr, vaddr + len) 中的页都已经分配并且可以按 access 的方式访问
+    pub fn fault_in(&mut self, vaddr: usize, len: usize, access: AccessType) -> Result<(), PagingError> {
+        let end = vaddr.checked_add(len).ok_or(PagingError::NotMapped)?;
+        let flags = PTEFlags::U | access.flags();
+        let mut page = vaddr / PAGE_SIZE * PAGE_SIZE;
+        while page < end {
+            let present = self
+                .page_table
+                .translate(page)
+                .map_or(false, |(_, pte_flags)| pte_flags.contains(flags));
+            if !present {
+                self.handle_page_fault(page, access)?;
+            }
+            page += PAGE_SIZE;
+        }
+        Ok(())
+    }
+
+    /// 把 data 写入地址空间中 vaddr 开始的位置，不检查页的权限
+    pub fn write(&self, vaddr: usize, data: &[u8]) -> Result<(), PagingError> {
+        self.copy_pages(vaddr, data.len(), PTEFlags::empty(), |page, offset| {
+            page.copy_from_slice(&data[offset..offset + page.len()])
+        })
+    }
+
+    /// 从用户地址 vaddr 读取 len 字节，要求这些页用户态可读
+    pub fn read_user(&mut self, vaddr: usize, len: usize) -> Result<Vec<u8>, PagingError> {
+        self.fault_in(vaddr, len, AccessType::Read)?;
+        let mut data = Vec::new();
+        self.copy_pages(vaddr, len, PTEFlags::U | PTEFlags::R, |page, _| {
+            data.extend_from_slice(page)
+        })?;
+        Ok(data)
+    }
+
+    /// 把 data 写入用户地址 vaddr，要求这些页用户态可写
+    pub fn write_user(&mut self, vaddr: usize, data: &[u8]) -> Result<(), PagingError> {
+        self.fault_in(vaddr, data.len(), AccessType::Write)?;
+        self.copy_pages(vaddr, data.len(), PTEFlags::U | PTEFlags::W, |page, offset| {
+            page.copy_from_slice(&data[offset..offset + page.len()])
+        })
+    }
+
+    /// 按页访问 [vaddr, vaddr + len)，f 的参数为页内的一段内存和它相对 vaddr 的偏移
+    ///
+    /// 先检查所有页都已经映射并且有 flags 权限，再依次调用 f，
+    /// 所以 len 不合法时不会访问任何内存
+    fn copy_pages(
+        &self,
+        vaddr: usize,
+        len: usize,
+        flags: PTEFlags,
+        mut f: impl FnMut(&mut [u8], usize),
+    ) -> Result<(), PagingError> {
+        let end = vaddr.checked_add(len).ok_or(PagingError::NotMapped)?;
+        let mut pages = Vec::new();
+        let mut current = vaddr;
+        while current < end {
+            let (paddr, pte_flags) = self.page_table.translate(current).ok_or(PagingError::NotMapped)?;
+            if !pte_flags.contains(flags) {
+                return Err(PagingError::NoPermission);
+            }
+            let page_len = (PAGE_SIZE - current % PAGE_SIZE).min(end - current);
+            pages.push((paddr, page_len));
+            current += page_len;
+        }
+        let mut offset = 0;
+        for (paddr, page_len) in pages {
+            let page = unsafe { core::slice::from_raw_parts_mut(paddr_to_virt(paddr) as *mut u8, page_len) };
+            f(page, offset);
+            offset += page_len;
+        }
+        Ok(())
+    }
+}
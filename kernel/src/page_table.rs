@@ -0,0 +1,377 @@
+//! Sv39 页表
+//!
+//! 三级页表，每级 512 项，支持 4K、2M、1G 三种页大小。
+//! 页表所在的页帧都从 frame 模块中分配。
+
+use alloc::vec::Vec;
+use bitflags::bitflags;
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use log::info;
+use spin::Mutex;
+
+use crate::frame::{frame_alloc, FrameError, TrackerFrame};
+
+/// 内核虚拟地址空间起始地址，物理地址加上该值即为内核中访问它的虚拟地址
+pub const VIRT_ADDR_START: usize = 0xffff_ffc0_0000_0000;
+
+/// 页大小
+pub const PAGE_SIZE: usize = 0x1000;
+
+/// 每个页表的页表项数量
+const ENTRY_COUNT: usize = 512;
+
+/// satp 中 Sv39 模式
+const SATP_MODE_SV39: usize = 8 << 60;
+
+/// satp 中根页表的物理页号
+const SATP_PPN_MASK: usize = (1 << 44) - 1;
+
+bitflags! {
+    /// 页表项标志位
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct PTEFlags: usize {
+        const V = 1 << 0;
+        const R = 1 << 1;
+        const W = 1 << 2;
+        const X = 1 << 3;
+        const U = 1 << 4;
+        const G = 1 << 5;
+        const A = 1 << 6;
+        const D = 1 << 7;
+    }
+}
+
+/// 页表项
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct PageTableEntry(usize);
+
+impl PageTableEntry {
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub const fn new(paddr: usize, flags: PTEFlags) -> Self {
+        Self((paddr >> 12) << 10 | flags.bits())
+    }
+
+    /// 页表项指向的物理地址
+    pub const fn paddr(&self) -> usize {
+        (self.0 >> 10) << 12
+    }
+
+    pub const fn flags(&self) -> PTEFlags {
+        PTEFlags::from_bits_truncate(self.0)
+    }
+
+    pub const fn is_valid(&self) -> bool {
+        self.flags().contains(PTEFlags::V)
+    }
+
+    /// R/W/X 任意一位被设置表示这是叶子节点
+    pub const fn is_leaf(&self) -> bool {
+        self.flags().intersects(PTEFlags::R.union(PTEFlags::W).union(PTEFlags::X))
+    }
+}
+
+/// 页大小
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageSize {
+    Size4K,
+    Size2M,
+    Size1G,
+}
+
+impl PageSize {
+    pub const fn size(self) -> usize {
+        match self {
+            PageSize::Size4K => 0x1000,
+            PageSize::Size2M => 0x20_0000,
+            PageSize::Size1G => 0x4000_0000,
+        }
+    }
+
+    /// 叶子页表项所在的页表级别
+    const fn level(self) -> usize {
+        match self {
+            PageSize::Size4K => 0,
+            PageSize::Size2M => 1,
+            PageSize::Size1G => 2,
+        }
+    }
+
+    const fn from_level(level: usize) -> Self {
+        match level {
+            0 => PageSize::Size4K,
+            1 => PageSize::Size2M,
+            _ => PageSize::Size1G,
+        }
+    }
+}
+
+/// 页表操作错误
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PagingError {
+    /// 地址没有按页大小对齐
+    NotAligned,
+    /// 地址已经被映射
+    AlreadyMapped,
+    /// 地址没有被映射
+    NotMapped,
+    /// 页表项没有需要的权限
+    NoPermission,
+    /// 没有内存分配页表
+    NoMemory,
+}
+
+impl From<FrameError> for PagingError {
+    fn from(_: FrameError) -> Self {
+        PagingError::NoMemory
+    }
+}
+
+/// 物理地址转换为内核虚拟地址
+#[inline]
+pub const fn paddr_to_virt(paddr: usize) -> usize {
+    paddr + VIRT_ADDR_START
+}
+
+/// 内核虚拟地址转换为物理地址
+#[inline]
+pub const fn virt_to_paddr(vaddr: usize) -> usize {
+    vaddr - VIRT_ADDR_START
+}
+
+/// 虚拟地址在 level 级页表中的下标
+#[inline]
+pub const fn vpn_index(vaddr: usize, level: usize) -> usize {
+    (vaddr >> (12 + level * 9)) & (ENTRY_COUNT - 1)
+}
+
+/// 获取物理地址处的页表
+#[inline]
+fn table_of(paddr: usize) -> &'static mut [PageTableEntry] {
+    unsafe {
+        core::slice::from_raw_parts_mut(paddr_to_virt(paddr) as *mut PageTableEntry, ENTRY_COUNT)
+    }
+}
+
+/// 刷新 TLB，vaddr 为 None 时刷新全部
+#[inline]
+pub fn flush_tlb(vaddr: Option<usize>) {
+    unsafe {
+        match vaddr {
+            Some(vaddr) => asm!("sfence.vma {}, zero", in(reg) vaddr),
+            None => asm!("sfence.vma"),
+        }
+    }
+}
+
+/// Sv39 页表
+pub struct PageTable {
+    /// 根页表
+    root: TrackerFrame,
+    /// 中间级页表占用的页帧
+    frames: Vec<TrackerFrame>,
+}
+
+impl PageTable {
+    /// 创建一个空页表
+    pub fn new() -> Result<Self, FrameError> {
+        Ok(Self {
+            root: frame_alloc()?,
+            frames: Vec::new(),
+        })
+    }
+
+    /// 根页表的物理地址
+    pub fn root_paddr(&self) -> usize {
+        self.root.paddr()
+    }
+
+    /// 对应的 satp 值
+    pub fn satp(&self) -> usize {
+        SATP_MODE_SV39 | (self.root_paddr() >> 12)
+    }
+
+    /// 创建一个用户页表，高地址的内核部分和内核页表共享
+    ///
+    /// 只复制根页表中的页表项，下级页表仍然属于内核页表
+    pub fn new_user() -> Result<Self, FrameError> {
+        let page_table = Self::new()?;
+        let kernel_root = table_of((kernel_satp() & SATP_PPN_MASK) << 12);
+        let user_root = table_of(page_table.root_paddr());
+        let start = vpn_index(VIRT_ADDR_START, 2);
+        user_root[start..].copy_from_slice(&kernel_root[start..]);
+        Ok(page_table)
+    }
+
+    /// 切换到当前页表
+    pub fn activate(&self) {
+        unsafe { asm!("csrw satp, {}", in(reg) self.satp()) };
+        flush_tlb(None);
+    }
+
+    /// 查找 vaddr 在 level 级页表中的页表项，不存在的中间页表会被创建
+    fn find_entry_create(
+        &mut self,
+        vaddr: usize,
+        level: usize,
+    ) -> Result<&'static mut PageTableEntry, PagingError> {
+        let mut table = table_of(self.root_paddr());
+        for current in (level + 1..=2).rev() {
+            let entry = &mut table[vpn_index(vaddr, current)];
+            if !entry.is_valid() {
+                let frame = frame_alloc()?;
+                *entry = PageTableEntry::new(frame.paddr(), PTEFlags::V);
+                self.frames.push(frame);
+            } else if entry.is_leaf() {
+                return Err(PagingError::AlreadyMapped);
+            }
+            table = table_of(entry.paddr());
+        }
+        Ok(&mut table[vpn_index(vaddr, level)])
+    }
+
+    /// 查找 vaddr 对应的叶子页表项
+    fn find_entry(&self, vaddr: usize) -> Option<(&'static mut PageTableEntry, PageSize)> {
+        let mut table = table_of(self.root_paddr());
+        for level in (0..=2).rev() {
+            let entry = &mut table[vpn_index(vaddr, level)];
+            if !entry.is_valid() {
+                return None;
+            }
+            if entry.is_leaf() {
+                return Some((entry, PageSize::from_level(level)));
+            }
+            table = table_of(entry.paddr());
+        }
+        None
+    }
+
+    /// 映射一个页
+    pub fn map(
+        &mut self,
+        vaddr: usize,
+        paddr: usize,
+        size: PageSize,
+        flags: PTEFlags,
+    ) -> Result<(), PagingError> {
+        if vaddr % size.size() != 0 || paddr % size.size() != 0 {
+            return Err(PagingError::NotAligned);
+        }
+        let entry = self.find_entry_create(vaddr, size.level())?;
+        if entry.is_valid() {
+            return Err(PagingError::AlreadyMapped);
+        }
+        *entry = PageTableEntry::new(paddr, flags | PTEFlags::V | PTEFlags::A | PTEFlags::D);
+        Ok(())
+    }
+
+    /// 取消映射，返回原来映射的物理地址和页大小
+    pub fn unmap(&mut self, vaddr: usize) -> Result<(usize, PageSize), PagingError> {
+        let (entry, size) = self.find_entry(vaddr).ok_or(PagingError::NotMapped)?;
+        if vaddr % size.size() != 0 {
+            return Err(PagingError::NotAligned);
+        }
+        let paddr = entry.paddr();
+        *entry = PageTableEntry::empty();
+        flush_tlb(Some(vaddr));
+        Ok((paddr, size))
+    }
+
+    /// 修改已经映射的页的权限
+    pub fn protect(&mut self, vaddr: usize, flags: PTEFlags) -> Result<(), PagingError> {
+        let (entry, _) = self.find_entry(vaddr).ok_or(PagingError::NotMapped)?;
+        *entry = PageTableEntry::new(entry.paddr(), flags | PTEFlags::V | PTEFlags::A | PTEFlags::D);
+        flush_tlb(Some(vaddr));
+        Ok(())
+    }
+
+    /// 虚拟地址转换为物理地址
+    pub fn translate(&self, vaddr: usize) -> Option<(usize, PTEFlags)> {
+        self.find_entry(vaddr)
+            .map(|(entry, size)| (entry.paddr() + vaddr % size.size(), entry.flags()))
+    }
+
+    /// 映射一段连续的区域，地址对齐时优先使用大页
+    pub fn map_region(
+        &mut self,
+        vaddr: usize,
+        paddr: usize,
+        size: usize,
+        flags: PTEFlags,
+    ) -> Result<(), PagingError> {
+        let mut offset = 0;
+        while offset < size {
+            let page_size = [PageSize::Size1G, PageSize::Size2M, PageSize::Size4K]
+                .into_iter()
+                .find(|page_size| {
+                    let ps = page_size.size();
+                    (vaddr + offset) % ps == 0 && (paddr + offset) % ps == 0 && size - offset >= ps
+                })
+                .ok_or(PagingError::NotAligned)?;
+            self.map(vaddr + offset, paddr + offset, page_size, flags)?;
+            offset += page_size.size();
+        }
+        Ok(())
+    }
+}
+
+/// 内核页表
+static KERNEL_PAGE_TABLE: Mutex<Option<PageTable>> = Mutex::new(None);
+
+/// 内核页表的 satp 值，切换任务时使用，不能加锁
+static KERNEL_SATP: AtomicUsize = AtomicUsize::new(0);
+
+/// 内核页表的 satp 值
+pub fn kernel_satp() -> usize {
+    KERNEL_SATP.load(Ordering::Relaxed)
+}
+
+/// 切换到 satp 对应的页表，和当前页表相同时不做任何事
+pub fn switch_satp(satp: usize) {
+    let current: usize;
+    unsafe { asm!("csrr {}, satp", out(reg) current) };
+    if current != satp {
+        unsafe { asm!("csrw satp, {}", in(reg) satp) };
+        flush_tlb(None);
+    }
+}
+
+/// 建立内核地址空间并切换到内核页表
+///
+/// 内核运行在 VIRT_ADDR_START 开始的高地址，代码段 RX，只读数据段 R，
+/// 数据段 (.data.prepage/.data/.bss) RW，内核之后到 mem_end 的物理内存 RW
+/// 供页帧分配器使用，物理地址 0 - 2G 的设备空间 RW。低地址留给用户空间。
+pub fn init_kernel_space(mem_end: usize) {
+    extern "C" {
+        fn stext();
+        fn srodata();
+        fn erodata();
+        fn _ekernel();
+    }
+
+    let mut page_table = PageTable::new().expect("can't allocate kernel page table");
+    let areas = [
+        (stext as usize, srodata as usize, PTEFlags::R | PTEFlags::X, ".text"),
+        (srodata as usize, erodata as usize, PTEFlags::R, ".rodata"),
+        (erodata as usize, _ekernel as usize, PTEFlags::R | PTEFlags::W, ".data"),
+        (_ekernel as usize, paddr_to_virt(mem_end), PTEFlags::R | PTEFlags::W, "physical memory"),
+        (paddr_to_virt(0), paddr_to_virt(0x8000_0000), PTEFlags::R | PTEFlags::W, "mmio"),
+    ];
+    for (start, end, flags, name) in areas {
+        info!("map {:<16} {:#x} - {:#x} {:?}", name, start, end, flags);
+        page_table
+            .map_region(start, virt_to_paddr(start), end - start, flags | PTEFlags::G)
+            .expect("can't map kernel area");
+    }
+    page_table.activate();
+    info!("kernel page table activated, satp: {:#x}", page_table.satp());
+
+    KERNEL_SATP.store(page_table.satp(), Ordering::Relaxed);
+    *KERNEL_PAGE_TABLE.lock() = Some(page_table);
+}
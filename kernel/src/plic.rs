@@ -0,0 +1,219 @@
+//! PLIC 平台级中断控制器驱动
+//!
+//! 从设备树中找到 riscv,plic0 节点，只使用启动核的 S 态上下文。
+//! 驱动通过 register_handler 按中断号注册处理函数，外部中断到来时
+//! 由 handle_irq 认领 (claim) 中断、调用处理函数，最后通知完成 (complete)。
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::ptr::{read_volatile, write_volatile};
+
+use fdt::node::FdtNode;
+use fdt::Fdt;
+use log::{info, warn};
+use spin::Mutex;
+
+use crate::page_table::paddr_to_virt;
+use crate::trap::without_interrupts;
+
+/// 中断优先级寄存器，每个中断源 4 字节
+const PRIORITY_BASE: usize = 0;
+/// 中断使能位，每个上下文 0x80 字节
+const ENABLE_BASE: usize = 0x2000;
+const ENABLE_STRIDE: usize = 0x80;
+/// 优先级阈值和认领/完成寄存器，每个上下文 0x1000 字节
+const CONTEXT_BASE: usize = 0x20_0000;
+const CONTEXT_STRIDE: usize = 0x1000;
+const CONTEXT_THRESHOLD: usize = 0;
+const CONTEXT_CLAIM: usize = 4;
+
+/// 设备树中 S 态外部中断在 CPU 中断控制器中的编号
+const IRQ_S_EXT: u32 = 9;
+
+/// 设备树中没有 riscv,ndev 时的中断源数量
+const DEFAULT_SOURCES: usize = 1024;
+
+/// 注册的中断默认优先级，大于阈值 0 才会触发
+const DEFAULT_PRIORITY: u32 = 1;
+
+/// sie 寄存器中的 SEIE 位
+const SIE_SEIE: usize = 1 << 9;
+
+/// 外部中断处理函数，在中断处理中调用
+pub type IrqHandler = fn();
+
+struct Plic {
+    base: usize,
+    /// 启动核 S 态的上下文编号
+    context: usize,
+    /// 中断源数量，中断号 0 保留
+    sources: usize,
+    handlers: BTreeMap<usize, IrqHandler>,
+}
+
+impl Plic {
+    fn read_reg(&self, offset: usize) -> u32 {
+        unsafe { read_volatile((self.base + offset) as *const u32) }
+    }
+
+    fn write_reg(&self, offset: usize, value: u32) {
+        unsafe { write_volatile((self.base + offset) as *mut u32, value) }
+    }
+
+    fn context_reg(&self, reg: usize) -> usize {
+        CONTEXT_BASE + self.context * CONTEXT_STRIDE + reg
+    }
+
+    fn set_priority(&self, irq: usize, priority: u32) {
+        self.write_reg(PRIORITY_BASE + irq * 4, priority);
+    }
+
+    fn set_threshold(&self, threshold: u32) {
+        self.write_reg(self.context_reg(CONTEXT_THRESHOLD), threshold);
+    }
+
+    fn set_enabled(&self, irq: usize, enabled: bool) {
+        let offset = ENABLE_BASE + self.context * ENABLE_STRIDE + irq / 32 * 4;
+        let bits = self.read_reg(offset);
+        let bit = 1 << (irq % 32);
+        self.write_reg(offset, if enabled { bits | bit } else { bits & !bit });
+    }
+
+    /// 认领优先级最高的待处理中断，没有时返回 0
+    fn claim(&self) -> usize {
+        self.read_reg(self.context_reg(CONTEXT_CLAIM)) as usize
+    }
+
+    fn complete(&self, irq: usize) {
+        self.write_reg(self.context_reg(CONTEXT_CLAIM), irq as u32);
+    }
+}
+
+static PLIC: Mutex<Option<Plic>> = Mutex::new(None);
+
+/// 读取大端序的 u32 数组
+fn cells(value: &[u8]) -> impl Iterator<Item = u32> + '_ {
+    value
+        .chunks_exact(4)
+        .map(|cell| u32::from_be_bytes(cell.try_into().unwrap()))
+}
+
+/// 找到 hart_id 对应 CPU 的中断控制器的 phandle
+fn cpu_intc_phandle(fdt: &Fdt, hart_id: usize) -> Option<u32> {
+    let cpu = fdt.find_node("/cpus")?.children().find(|cpu| {
+        cpu.name.starts_with("cpu@")
+            && cpu.reg().and_then(|mut reg| reg.next()).map(|reg| reg.starting_address as usize)
+                == Some(hart_id)
+    })?;
+    let intc = cpu.children().find(|child| child.name.starts_with("interrupt-controller"))?;
+    intc.property("phandle")?.as_usize().map(|phandle| phandle as u32)
+}
+
+/// 从 interrupts-extended 中找到 hart_id 的 S 态外部中断对应的上下文编号
+///
+/// 每个上下文是一对 (CPU 中断控制器 phandle, 中断编号)，找不到时按
+/// QEMU virt 的布局每个核依次是 M 态和 S 态
+fn find_context(fdt: &Fdt, node: &FdtNode, hart_id: usize) -> usize {
+    let context = cpu_intc_phandle(fdt, hart_id).and_then(|phandle| {
+        let prop = node.property("interrupts-extended")?;
+        let cells: Vec<u32> = cells(prop.value).collect();
+        cells
+            .chunks_exact(2)
+            .position(|pair| pair[0] == phandle && pair[1] == IRQ_S_EXT)
+    });
+    context.unwrap_or_else(|| {
+        warn!("plic: can't find s-mode context of hart {} in device tree", hart_id);
+        hart_id * 2 + 1
+    })
+}
+
+/// 从设备树中找到 PLIC 并初始化，打开外部中断
+///
+/// 所有中断源先关闭，驱动注册处理函数时再打开
+pub fn init(fdt: &Fdt, hart_id: usize) {
+    let Some(node) = fdt.find_compatible(&["riscv,plic0", "sifive,plic-1.0.0"]) else {
+        warn!("plic: no plic in device tree, external interrupts disabled");
+        return;
+    };
+    let Some(region) = node.reg().and_then(|mut reg| reg.next()) else {
+        warn!("plic: {} has no reg property", node.name);
+        return;
+    };
+    let plic = Plic {
+        base: paddr_to_virt(region.starting_address as usize),
+        context: find_context(fdt, &node, hart_id),
+        sources: node
+            .property("riscv,ndev")
+            .and_then(|prop| prop.as_usize())
+            .map(|ndev| ndev + 1)
+            .unwrap_or(DEFAULT_SOURCES),
+        handlers: BTreeMap::new(),
+    };
+    for irq in 1..plic.sources {
+        plic.set_enabled(irq, false);
+    }
+    plic.set_threshold(0);
+    info!(
+        "plic: {} at {:#x}, {} sources, context {}",
+        node.name,
+        region.starting_address as usize,
+        plic.sources - 1,
+        plic.context
+    );
+    without_interrupts(|| *PLIC.lock() = Some(plic));
+    unsafe { asm!("csrs sie, {}", in(reg) SIE_SEIE) };
+}
+
+/// 设备树节点 interrupts 属性中的第一个中断号
+pub fn irq_of(node: &FdtNode) -> Option<usize> {
+    node.interrupts().and_then(|mut irqs| irqs.next())
+}
+
+/// 注册中断处理函数并打开中断，同一个中断号的处理函数会被替换
+///
+/// PLIC 没有初始化或中断号超出范围时返回 false，这时驱动需要自己轮询
+pub fn register_handler(irq: usize, handler: IrqHandler) -> bool {
+    without_interrupts(|| {
+        let mut plic = PLIC.lock();
+        let Some(plic) = plic.as_mut() else {
+            return false;
+        };
+        if irq == 0 || irq >= plic.sources {
+            return false;
+        }
+        plic.handlers.insert(irq, handler);
+        plic.set_priority(irq, DEFAULT_PRIORITY);
+        plic.set_enabled(irq, true);
+        true
+    })
+}
+
+/// 外部中断处理，处理所有待处理的中断
+///
+/// 调用处理函数时不持有 PLIC 的锁，处理函数中可以注册其他中断
+pub fn handle_irq() {
+    loop {
+        let claimed = PLIC.lock().as_ref().map(|plic| {
+            let irq = plic.claim();
+            (irq, plic.handlers.get(&irq).copied())
+        });
+        let Some((irq, handler)) = claimed else {
+            return;
+        };
+        if irq == 0 {
+            return;
+        }
+        match handler {
+            Some(handler) => handler(),
+            None => warn!("plic: unexpected irq {}", irq),
+        }
+        let plic = PLIC.lock();
+        if let Some(plic) = plic.as_ref() {
+            if handler.is_none() {
+                plic.set_enabled(irq, false);
+            }
+            plic.complete(irq);
+        }
+    }
+}
@@ -0,0 +1,206 @@
+//! ELF64 可执行文件解析与加载
+//!
+//! 只支持 RISC-V 小端静态链接的可执行文件 (ET_EXEC)，
+//! 把 PT_LOAD 段按权限映射到用户地址空间。
+
+use crate::memory_set::{Backing, MemorySet};
+use crate::page_table::{PTEFlags, PagingError, PAGE_SIZE};
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_RISCV: u16 = 243;
+
+const PT_LOAD: u32 = 1;
+const PT_INTERP: u32 = 3;
+const PT_PHDR: u32 = 6;
+
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+/// ELF 头大小
+const EHDR_SIZE: usize = 64;
+
+/// 程序头大小
+const PHDR_SIZE: usize = 56;
+
+/// ELF 解析或加载错误
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    /// 不是 ELF 文件
+    BadMagic,
+    /// 不是 RISC-V 64 位小端格式
+    Unsupported,
+    /// 不是静态链接的可执行文件
+    NotExecutable,
+    /// 文件内容不完整
+    Truncated,
+    /// 段的地址不合法
+    BadSegment,
+    /// 映射段时出错
+    Paging(PagingError),
+}
+
+impl From<PagingError> for ElfError {
+    fn from(err: PagingError) -> Self {
+        ElfError::Paging(err)
+    }
+}
+
+/// 程序头
+#[derive(Debug, Clone, Copy)]
+pub struct ProgramHeader {
+    pub p_type: u32,
+    pub flags: u32,
+    pub offset: usize,
+    pub vaddr: usize,
+    pub file_size: usize,
+    pub mem_size: usize,
+}
+
+impl ProgramHeader {
+    /// 段对应的页表权限
+    fn pte_flags(&self) -> PTEFlags {
+        let mut flags = PTEFlags::U;
+        if self.flags & PF_R != 0 {
+            flags |= PTEFlags::R;
+        }
+        if self.flags & PF_W != 0 {
+            flags |= PTEFlags::W | PTEFlags::R;
+        }
+        if self.flags & PF_X != 0 {
+            flags |= PTEFlags::X;
+        }
+        flags
+    }
+}
+
+/// 解析后的 ELF 文件
+pub struct Elf<'a> {
+    data: &'a [u8],
+    pub entry: usize,
+    phoff: usize,
+    phnum: usize,
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> usize {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap()) as usize
+}
+
+impl<'a> Elf<'a> {
+    /// 检查 ELF 头
+    pub fn parse(data: &'a [u8]) -> Result<Self, ElfError> {
+        if data.len() < EHDR_SIZE {
+            return Err(ElfError::Truncated);
+        }
+        if data[..4] != ELF_MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if data[4] != ELFCLASS64 || data[5] != ELFDATA2LSB || read_u16(data, 18) != EM_RISCV {
+            return Err(ElfError::Unsupported);
+        }
+        if read_u16(data, 16) != ET_EXEC {
+            return Err(ElfError::NotExecutable);
+        }
+        let phoff = read_u64(data, 32);
+        let phnum = read_u16(data, 56) as usize;
+        if read_u16(data, 54) as usize != PHDR_SIZE
+            || phoff.checked_add(phnum * PHDR_SIZE).map_or(true, |end| end > data.len())
+        {
+            return Err(ElfError::Truncated);
+        }
+        let elf = Self {
+            data,
+            entry: read_u64(data, 24),
+            phoff,
+            phnum,
+        };
+        if elf.program_headers().any(|ph| ph.p_type == PT_INTERP) {
+            return Err(ElfError::NotExecutable);
+        }
+        Ok(elf)
+    }
+
+    /// 所有程序头
+    pub fn program_headers(&self) -> impl Iterator<Item = ProgramHeader> + '_ {
+        (0..self.phnum).map(|i| {
+            let offset = self.phoff + i * PHDR_SIZE;
+            ProgramHeader {
+                p_type: read_u32(self.data, offset),
+                flags: read_u32(self.data, offset + 4),
+                offset: read_u64(self.data, offset + 8),
+                vaddr: read_u64(self.data, offset + 16),
+                file_size: read_u64(self.data, offset + 32),
+                mem_size: read_u64(self.data, offset + 40),
+            }
+        })
+    }
+
+    /// 程序头在用户地址空间中的地址，用于 AT_PHDR
+    pub fn phdr_vaddr(&self) -> Option<usize> {
+        if let Some(phdr) = self.program_headers().find(|ph| ph.p_type == PT_PHDR) {
+            return Some(phdr.vaddr);
+        }
+        self.program_headers()
+            .filter(|ph| ph.p_type == PT_LOAD)
+            .find(|ph| {
+                ph.offset
+                    .checked_add(ph.file_size)
+                    .is_some_and(|file_end| (ph.offset..file_end).contains(&self.phoff))
+            })
+            .and_then(|ph| ph.vaddr.checked_add(self.phoff - ph.offset))
+    }
+
+    pub fn phnum(&self) -> usize {
+        self.phnum
+    }
+
+    pub fn phent(&self) -> usize {
+        PHDR_SIZE
+    }
+
+}
+
+impl Elf<'static> {
+    /// 把 PT_LOAD 段映射到 memory_set 中，返回最后一个段结束的地址 (页对齐)
+    ///
+    /// 段映射为文件后备的私有区域，访问时才从 ELF 文件中复制，
+    /// 段中超出文件内容的部分 (.bss) 为 0
+    pub fn load(&self, memory_set: &mut MemorySet, user_end: usize) -> Result<usize, ElfError> {
+        let mut end = 0;
+        for ph in self.program_headers().filter(|ph| ph.p_type == PT_LOAD) {
+            let seg_end = ph.vaddr.checked_add(ph.mem_size).ok_or(ElfError::BadSegment)?;
+            if ph.file_size > ph.mem_size || seg_end > user_end {
+                return Err(ElfError::BadSegment);
+            }
+            let file_end = ph.offset.checked_add(ph.file_size).ok_or(ElfError::Truncated)?;
+            let data = self.data.get(ph.offset..file_end).ok_or(ElfError::Truncated)?;
+            let start = ph.vaddr / PAGE_SIZE * PAGE_SIZE;
+            let seg_end = (seg_end + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE;
+            match ph.offset.checked_sub(ph.vaddr - start) {
+                // 区域从页边界开始，对应文件中段之前的内容
+                Some(offset) => {
+                    let data = &self.data[offset..file_end];
+                    memory_set.map(start, seg_end, ph.pte_flags(), Backing::File { data }, false)?;
+                }
+                // 文件中段之前的内容不足，只能直接复制
+                None => {
+                    memory_set.map_framed(start, seg_end, ph.pte_flags())?;
+                    memory_set.write(ph.vaddr, data)?;
+                }
+            }
+            end = end.max(seg_end);
+        }
+        Ok(end)
+    }
+}
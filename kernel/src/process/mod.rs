@@ -0,0 +1,465 @@
+//! 用户进程
+//!
+//! 每个用户进程由一个任务运行，任务在内核栈上构造 TrapFrame 后通过 sret 进入用户态，
+//! 用户态的 ecall 和异常经过 trap_vector 回到内核。
+//!
+//! 进程退出后成为僵尸进程，保留在进程表中直到父进程通过 wait4 回收，
+//! 父进程先退出时子进程交给 init 进程。
+
+mod elf;
+
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::{
+    mem,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use log::{info, warn};
+use spin::{Mutex, MutexGuard};
+
+use crate::memory_set::{AccessType, MemorySet};
+use crate::page_table::{switch_satp, PTEFlags, PagingError, PAGE_SIZE};
+use crate::task::{self, WaitQueue};
+use crate::timer;
+use crate::trap::{Exception, Trap, TrapFrame};
+
+pub use elf::{Elf, ElfError};
+
+/// 用户地址空间的结束地址，Sv39 低地址的一半
+pub const USER_END: usize = 0x40_0000_0000;
+
+/// 用户栈栈顶
+pub const USER_STACK_TOP: usize = USER_END;
+
+/// 用户栈大小
+pub const USER_STACK_SIZE: usize = 0x2_0000;
+
+// 辅助向量的类型
+const AT_NULL: usize = 0;
+const AT_PHDR: usize = 3;
+const AT_PHENT: usize = 4;
+const AT_PHNUM: usize = 5;
+const AT_PAGESZ: usize = 6;
+const AT_BASE: usize = 7;
+const AT_FLAGS: usize = 8;
+const AT_ENTRY: usize = 9;
+const AT_UID: usize = 11;
+const AT_EUID: usize = 12;
+const AT_GID: usize = 13;
+const AT_EGID: usize = 14;
+const AT_HWCAP: usize = 16;
+const AT_CLKTCK: usize = 17;
+const AT_SECURE: usize = 23;
+const AT_RANDOM: usize = 25;
+
+/// 用户程序看到的时钟频率 (Linux 的 USER_HZ)
+const USER_HZ: usize = 100;
+
+include!(concat!(env!("OUT_DIR"), "/programs.rs"));
+
+/// 查找编译时嵌入的用户程序，路径中的目录会被忽略
+pub fn find_program(path: &str) -> Option<&'static [u8]> {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    PROGRAMS
+        .iter()
+        .find(|(program, _)| *program == name)
+        .map(|(_, data)| *data)
+}
+
+/// 下一个进程 id
+static NEXT_PID: AtomicUsize = AtomicUsize::new(1);
+
+/// 所有还没有被回收的进程
+static PROCESSES: Mutex<BTreeMap<usize, Arc<Process>>> = Mutex::new(BTreeMap::new());
+
+/// init 进程，孤儿进程交给它回收
+static INIT_PROCESS: Mutex<Option<Arc<Process>>> = Mutex::new(None);
+
+/// 信号编号，和 Linux 相同。还不支持信号处理函数，收到这些信号的进程直接结束
+const SIGILL: i32 = 4;
+const SIGTRAP: i32 = 5;
+const SIGBUS: i32 = 7;
+const SIGSEGV: i32 = 11;
+
+/// 正常退出时 wait4 得到的状态
+pub const fn exit_status(code: i32) -> i32 {
+    (code & 0xff) << 8
+}
+
+struct ProcessInner {
+    parent: Option<Weak<Process>>,
+    children: Vec<Arc<Process>>,
+    /// 退出后 wait4 得到的状态，Some 表示已经退出，等待父进程回收
+    exit_status: Option<i32>,
+}
+
+/// 用户进程
+pub struct Process {
+    pid: usize,
+    name: Mutex<String>,
+    memory_set: Mutex<MemorySet>,
+    /// 地址空间的 satp 值，切换任务时使用，不能加锁
+    satp: AtomicUsize,
+    inner: Mutex<ProcessInner>,
+    /// 等待子进程退出的任务
+    child_exited: WaitQueue,
+}
+
+impl Process {
+    fn new(name: &str, memory_set: MemorySet) -> Arc<Self> {
+        Arc::new(Self {
+            pid: NEXT_PID.fetch_add(1, Ordering::Relaxed),
+            name: Mutex::new(name.to_string()),
+            satp: AtomicUsize::new(memory_set.satp()),
+            memory_set: Mutex::new(memory_set),
+            inner: Mutex::new(ProcessInner {
+                parent: None,
+                children: Vec::new(),
+                exit_status: None,
+            }),
+            child_exited: WaitQueue::new(),
+        })
+    }
+
+    pub fn pid(&self) -> usize {
+        self.pid
+    }
+
+    pub fn name(&self) -> String {
+        self.name.lock().clone()
+    }
+
+    pub fn satp(&self) -> usize {
+        self.satp.load(Ordering::Relaxed)
+    }
+
+    pub fn memory_set(&self) -> MutexGuard<'_, MemorySet> {
+        self.memory_set.lock()
+    }
+
+    pub fn parent(&self) -> Option<Arc<Process>> {
+        self.inner.lock().parent.as_ref().and_then(Weak::upgrade)
+    }
+}
+
+/// 加入进程表，有父进程时加入父进程的子进程列表
+fn register(process: &Arc<Process>, parent: Option<&Arc<Process>>) {
+    PROCESSES.lock().insert(process.pid, process.clone());
+    if let Some(parent) = parent {
+        process.inner.lock().parent = Some(Arc::downgrade(parent));
+        parent.inner.lock().children.push(process.clone());
+    }
+}
+
+/// 创建任务失败时撤销 register
+fn unregister(process: &Arc<Process>, parent: Option<&Arc<Process>>) {
+    PROCESSES.lock().remove(&process.pid);
+    if let Some(parent) = parent {
+        parent
+            .inner
+            .lock()
+            .children
+            .retain(|child| !Arc::ptr_eq(child, process));
+    }
+}
+
+/// 创建运行 process 的任务，从 tf 开始执行用户程序
+fn spawn_task(
+    process: &Arc<Process>,
+    parent: Option<&Arc<Process>>,
+    tf: TrapFrame,
+) -> Result<(), PagingError> {
+    register(process, parent);
+    if let Err(err) = task::spawn_user(&process.name(), process.clone(), tf) {
+        unregister(process, parent);
+        return Err(err.into());
+    }
+    Ok(())
+}
+
+/// 把 data 放到用户栈上，返回它的地址
+fn push_bytes(memory_set: &MemorySet, sp: &mut usize, data: &[u8]) -> Result<usize, PagingError> {
+    *sp -= data.len();
+    memory_set.write(*sp, data)?;
+    Ok(*sp)
+}
+
+/// 把以 0 结尾的字符串放到用户栈上，返回它的地址
+fn push_str(memory_set: &MemorySet, sp: &mut usize, s: &str) -> Result<usize, PagingError> {
+    push_bytes(memory_set, sp, &[0])?;
+    push_bytes(memory_set, sp, s.as_bytes())
+}
+
+/// 初始化用户栈，返回用户程序开始运行时的 sp
+///
+/// 从栈顶向下依次是字符串和随机数，然后是 auxv、envp、argv 和 argc，sp 指向 argc
+fn init_user_stack(
+    memory_set: &MemorySet,
+    elf: &Elf,
+    argv: &[&str],
+    envp: &[&str],
+) -> Result<usize, PagingError> {
+    let mut sp = USER_STACK_TOP;
+    let envp_ptrs = envp
+        .iter()
+        .map(|s| push_str(memory_set, &mut sp, s))
+        .collect::<Result<Vec<_>, _>>()?;
+    let argv_ptrs = argv
+        .iter()
+        .map(|s| push_str(memory_set, &mut sp, s))
+        .collect::<Result<Vec<_>, _>>()?;
+    // 给 libc 初始化栈保护用，不是真正的随机数
+    let seed = (timer::get_time() as u128).wrapping_mul(0x9e37_79b9_7f4a_7c15_f39c_c060_5ced_c835);
+    let random = push_bytes(memory_set, &mut sp, &seed.to_le_bytes())?;
+
+    let auxv = [
+        (AT_PHDR, elf.phdr_vaddr().unwrap_or(0)),
+        (AT_PHENT, elf.phent()),
+        (AT_PHNUM, elf.phnum()),
+        (AT_PAGESZ, PAGE_SIZE),
+        (AT_BASE, 0),
+        (AT_FLAGS, 0),
+        (AT_ENTRY, elf.entry),
+        (AT_UID, 0),
+        (AT_EUID, 0),
+        (AT_GID, 0),
+        (AT_EGID, 0),
+        (AT_HWCAP, 0),
+        (AT_CLKTCK, USER_HZ),
+        (AT_SECURE, 0),
+        (AT_RANDOM, random),
+        (AT_NULL, 0),
+    ];
+    let mut words = Vec::new();
+    words.push(argv.len());
+    words.extend(argv_ptrs);
+    words.push(0);
+    words.extend(envp_ptrs);
+    words.push(0);
+    words.extend(auxv.iter().flat_map(|(key, value)| [*key, *value]));
+
+    sp = (sp - words.len() * 8) & !0xf;
+    let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+    memory_set.write(sp, &bytes)?;
+    Ok(sp)
+}
+
+/// 加载 ELF 文件到新的地址空间，返回地址空间、入口地址和用户栈
+fn load_program(
+    data: &'static [u8],
+    argv: &[&str],
+    envp: &[&str],
+) -> Result<(MemorySet, usize, usize), ElfError> {
+    let elf = Elf::parse(data)?;
+    let mut memory_set = MemorySet::new_user()?;
+    let stack_bottom = USER_STACK_TOP - USER_STACK_SIZE;
+    let program_end = elf.load(&mut memory_set, stack_bottom)?;
+    memory_set.heap_start = program_end;
+    memory_set.brk = program_end;
+    memory_set.map_framed(
+        stack_bottom,
+        USER_STACK_TOP,
+        PTEFlags::U | PTEFlags::R | PTEFlags::W,
+    )?;
+    let sp = init_user_stack(&memory_set, &elf, argv, envp)?;
+    Ok((memory_set, elf.entry, sp))
+}
+
+/// 从 ELF 文件创建没有父进程的用户进程，返回进程 id
+///
+/// 第一个这样创建的进程成为 init 进程
+pub fn spawn_user(name: &str, data: &'static [u8], argv: &[&str], envp: &[&str]) -> Result<usize, ElfError> {
+    let (memory_set, entry, sp) = load_program(data, argv, envp)?;
+    let process = Process::new(name, memory_set);
+    info!(
+        "process {} (pid {}): entry {:#x}, sp {:#x}",
+        name, process.pid, entry, sp
+    );
+    spawn_task(&process, None, TrapFrame::new_user(entry, sp))?;
+    INIT_PROCESS.lock().get_or_insert_with(|| process.clone());
+    Ok(process.pid)
+}
+
+/// 复制当前进程，子进程从 tf 返回，返回值为 0。stack 不为 0 时作为子进程的 sp
+///
+/// 返回子进程 id
+pub fn fork(tf: &TrapFrame, stack: usize) -> Result<usize, PagingError> {
+    let parent = current_process().expect("fork from kernel thread");
+    let memory_set = parent.memory_set().fork()?;
+    let child = Process::new(&parent.name(), memory_set);
+    let mut child_tf = *tf;
+    child_tf.x[10] = 0;
+    if stack != 0 {
+        child_tf.x[2] = stack;
+    }
+    spawn_task(&child, Some(&parent), child_tf)?;
+    Ok(child.pid)
+}
+
+/// 用 ELF 文件替换当前进程的地址空间，成功后 tf 指向新程序的入口
+pub fn exec(
+    tf: &mut TrapFrame,
+    name: &str,
+    data: &'static [u8],
+    argv: &[&str],
+    envp: &[&str],
+) -> Result<(), ElfError> {
+    let (memory_set, entry, sp) = load_program(data, argv, envp)?;
+    let process = current_process().expect("exec from kernel thread");
+    let satp = memory_set.satp();
+    let old = mem::replace(&mut *process.memory_set(), memory_set);
+    process.satp.store(satp, Ordering::Relaxed);
+    // 先切换到新的页表再释放原来的地址空间
+    switch_satp(satp);
+    drop(old);
+    *process.name.lock() = name.to_string();
+    *tf = TrapFrame::new_user(entry, sp);
+    Ok(())
+}
+
+/// 等待子进程退出并回收它，返回子进程 id 和退出状态
+///
+/// pid 大于 0 时等待指定的子进程，否则等待任意子进程。没有符合条件的子进程时返回 None，
+/// nohang 为 true 并且子进程都没有退出时返回 Some((0, 0))
+pub fn wait_child(pid: isize, nohang: bool) -> Option<(usize, i32)> {
+    let process = current_process()?;
+    let matches = |child: &Arc<Process>| pid <= 0 || child.pid == pid as usize;
+    process.child_exited.wait_until(|| {
+        let mut inner = process.inner.lock();
+        if !inner.children.iter().any(matches) {
+            return Some(None);
+        }
+        let exited = inner
+            .children
+            .iter()
+            .position(|child| matches(child) && child.inner.lock().exit_status.is_some());
+        match exited {
+            Some(index) => {
+                let child = inner.children.remove(index);
+                PROCESSES.lock().remove(&child.pid);
+                let status = child.inner.lock().exit_status.unwrap_or(0);
+                Some(Some((child.pid, status)))
+            }
+            None if nohang => Some(Some((0, 0))),
+            None => None,
+        }
+    })
+}
+
+/// 当前任务所属的进程
+pub fn current_process() -> Option<Arc<Process>> {
+    task::current().and_then(|task| task.process().cloned())
+}
+
+/// 把退出进程的子进程交给 init 进程
+fn reparent(exiting: &Arc<Process>, children: Vec<Arc<Process>>) {
+    let init = {
+        let mut init = INIT_PROCESS.lock();
+        if init.as_ref().map_or(false, |init| Arc::ptr_eq(init, exiting)) {
+            init.take();
+        }
+        init.clone()
+    };
+    for child in children {
+        let exited = {
+            let mut inner = child.inner.lock();
+            inner.parent = init.as_ref().map(Arc::downgrade);
+            inner.exit_status.is_some()
+        };
+        match &init {
+            Some(init) => init.inner.lock().children.push(child),
+            // 没有进程会回收它了
+            None if exited => {
+                PROCESSES.lock().remove(&child.pid);
+            }
+            None => {}
+        }
+    }
+    if let Some(init) = init {
+        init.child_exited.wake_all();
+    }
+}
+
+/// 结束当前进程，status 为 wait4 得到的状态
+pub fn exit_with_status(status: i32) -> ! {
+    if let Some(process) = current_process() {
+        info!(
+            "process {} (pid {}) exited with status {:#x}",
+            process.name(),
+            process.pid(),
+            status
+        );
+        let children = {
+            let mut inner = process.inner.lock();
+            inner.exit_status = Some(status);
+            mem::take(&mut inner.children)
+        };
+        reparent(&process, children);
+        match process.parent() {
+            Some(parent) => parent.child_exited.wake_all(),
+            None => {
+                PROCESSES.lock().remove(&process.pid);
+            }
+        }
+    }
+    task::exit()
+}
+
+/// 结束当前进程
+pub fn exit(code: i32) -> ! {
+    exit_with_status(exit_status(code))
+}
+
+/// 结束当前进程，wait4 得到的状态为信号编号
+fn kill(signal: i32) -> ! {
+    exit_with_status(signal)
+}
+
+/// 用户态缺页，在地址空间中分配或复制页，地址不合法时向进程发送 SIGSEGV
+pub fn handle_page_fault(trap: Trap, tf: &TrapFrame) {
+    let access = match trap {
+        Trap::Exception(Exception::InstructionPageFault) => AccessType::Execute,
+        Trap::Exception(Exception::LoadPageFault) => AccessType::Read,
+        _ => AccessType::Write,
+    };
+    let result = match current_process() {
+        Some(process) => process.memory_set().handle_page_fault(tf.stval, access),
+        None => Err(PagingError::NotMapped),
+    };
+    if let Err(err) = result {
+        warn!(
+            "segmentation fault: {:?} {:#x} at sepc {:#x}: {:?}",
+            access, tf.stval, tf.sepc, err
+        );
+        if let Some(process) = current_process() {
+            if let Some(area) = process.memory_set().find_area(tf.stval) {
+                warn!(
+                    "{:#x} in area {:#x} - {:#x} {:?} {:?}",
+                    tf.stval, area.start, area.end, area.flags, area.backing
+                );
+            }
+        }
+        kill(SIGSEGV);
+    }
+}
+
+/// 用户态发生了无法处理的异常，向进程发送对应的信号
+pub fn handle_user_exception(trap: Trap, tf: &TrapFrame) -> ! {
+    let signal = match trap {
+        Trap::Exception(Exception::IllegalInstruction) => SIGILL,
+        Trap::Exception(Exception::Breakpoint) => SIGTRAP,
+        Trap::Exception(Exception::InstructionMisaligned)
+        | Trap::Exception(Exception::LoadMisaligned)
+        | Trap::Exception(Exception::StoreMisaligned) => SIGBUS,
+        _ => SIGSEGV,
+    };
+    warn!(
+        "user exception {:?}, sepc: {:#x}, stval: {:#x}, signal {}",
+        trap, tf.sepc, tf.stval, signal
+    );
+    kill(signal)
+}
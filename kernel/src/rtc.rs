@@ -0,0 +1,156 @@
+//! Goldfish RTC 驱动
+//!
+//! 从设备树中找到 google,goldfish-rtc 节点。时间是自 1970 年以来的纳秒数，
+//! 读取 TIME_LOW 时硬件锁存高 32 位，所以必须先读低位再读高位。
+//! 闹钟到期时通过 PLIC 产生中断，调用设置闹钟时传入的处理函数。
+
+use core::ptr::{read_volatile, write_volatile};
+
+use fdt::Fdt;
+use log::{info, warn};
+use spin::Mutex;
+use timestamp::DateTime;
+
+use crate::page_table::paddr_to_virt;
+use crate::plic;
+use crate::trap::without_interrupts;
+
+const TIME_LOW: usize = 0x00;
+const TIME_HIGH: usize = 0x04;
+/// 写入 ALARM_LOW 时闹钟生效，需要先写高位
+const ALARM_LOW: usize = 0x08;
+const ALARM_HIGH: usize = 0x0c;
+const IRQ_ENABLED: usize = 0x10;
+const CLEAR_ALARM: usize = 0x14;
+const CLEAR_INTERRUPT: usize = 0x1c;
+
+pub const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// 闹钟处理函数，在中断处理中调用
+pub type AlarmHandler = fn();
+
+struct GoldfishRtc {
+    base: usize,
+    /// 闹钟中断是否已经通过 PLIC 注册
+    has_irq: bool,
+    alarm: Option<AlarmHandler>,
+}
+
+impl GoldfishRtc {
+    fn read_reg(&self, reg: usize) -> u32 {
+        unsafe { read_volatile((self.base + reg) as *const u32) }
+    }
+
+    fn write_reg(&self, reg: usize, value: u32) {
+        unsafe { write_volatile((self.base + reg) as *mut u32, value) }
+    }
+
+    fn time(&self) -> u64 {
+        let low = self.read_reg(TIME_LOW);
+        let high = self.read_reg(TIME_HIGH);
+        ((high as u64) << 32) | low as u64
+    }
+
+    fn set_time(&self, nanos: u64) {
+        self.write_reg(TIME_HIGH, (nanos >> 32) as u32);
+        self.write_reg(TIME_LOW, nanos as u32);
+    }
+
+    fn set_alarm(&self, nanos: u64) {
+        self.write_reg(IRQ_ENABLED, 1);
+        self.write_reg(ALARM_HIGH, (nanos >> 32) as u32);
+        self.write_reg(ALARM_LOW, nanos as u32);
+    }
+
+    fn clear_alarm(&self) {
+        self.write_reg(CLEAR_ALARM, 1);
+        self.write_reg(CLEAR_INTERRUPT, 1);
+    }
+}
+
+static RTC: Mutex<Option<GoldfishRtc>> = Mutex::new(None);
+
+/// 从设备树中找到 RTC 并初始化，注册闹钟中断
+pub fn init(fdt: &Fdt) {
+    let Some(node) = fdt.find_compatible(&["google,goldfish-rtc"]) else {
+        warn!("rtc: no goldfish rtc in device tree");
+        return;
+    };
+    let Some(region) = node.reg().and_then(|mut reg| reg.next()) else {
+        warn!("rtc: {} has no reg property", node.name);
+        return;
+    };
+    let irq = plic::irq_of(&node);
+    let rtc = GoldfishRtc {
+        base: paddr_to_virt(region.starting_address as usize),
+        has_irq: irq.is_some_and(|irq| plic::register_handler(irq, handle_irq)),
+        alarm: None,
+    };
+    rtc.clear_alarm();
+    info!(
+        "rtc: {} at {:#x}, irq {:?}, time {}",
+        node.name,
+        region.starting_address as usize,
+        irq,
+        DateTime::new((rtc.time() / NANOS_PER_SEC) as usize)
+    );
+    without_interrupts(|| *RTC.lock() = Some(rtc));
+}
+
+/// 自 1970 年以来的纳秒数，没有 RTC 时返回 None
+pub fn time_nanos() -> Option<u64> {
+    without_interrupts(|| RTC.lock().as_ref().map(GoldfishRtc::time))
+}
+
+/// 设置 RTC 的时间，没有 RTC 时返回 false
+pub fn set_time_nanos(nanos: u64) -> bool {
+    without_interrupts(|| {
+        let rtc = RTC.lock();
+        let Some(rtc) = rtc.as_ref() else {
+            return false;
+        };
+        rtc.set_time(nanos);
+        true
+    })
+}
+
+/// 当前的日期和时间
+pub fn wall_clock() -> Option<DateTime> {
+    time_nanos().map(|nanos| DateTime::new((nanos / NANOS_PER_SEC) as usize))
+}
+
+/// 设置闹钟，RTC 时间到达 nanos 时调用 handler，之前设置的闹钟被替换
+///
+/// 没有 RTC 或闹钟中断不可用时返回 false
+pub fn set_alarm(nanos: u64, handler: AlarmHandler) -> bool {
+    without_interrupts(|| {
+        let mut rtc = RTC.lock();
+        let Some(rtc) = rtc.as_mut().filter(|rtc| rtc.has_irq) else {
+            return false;
+        };
+        rtc.alarm = Some(handler);
+        rtc.set_alarm(nanos);
+        true
+    })
+}
+
+/// 取消还没有到期的闹钟
+pub fn cancel_alarm() {
+    without_interrupts(|| {
+        if let Some(rtc) = RTC.lock().as_mut() {
+            rtc.alarm = None;
+            rtc.clear_alarm();
+        }
+    });
+}
+
+/// 闹钟中断处理，闹钟只触发一次
+fn handle_irq() {
+    let alarm = RTC.lock().as_mut().and_then(|rtc| {
+        rtc.write_reg(CLEAR_INTERRUPT, 1);
+        rtc.alarm.take()
+    });
+    if let Some(alarm) = alarm {
+        alarm();
+    }
+}
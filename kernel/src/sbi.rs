@@ -40,10 +40,11 @@ pub fn console_putchar(ch: u8) {
     sbi_call(SBI_CONSOLE_PUT_CHAR, ch as usize, 0, 0);
 }
 
-/// 获取输入
+/// 获取输入，没有输入时返回 None (SBI 返回 -1)
 #[inline]
-pub fn console_getchar() -> char {
-    sbi_call(SBI_CONSOLE_GET_CHAR, 0, 0, 0) as u8 as char
+pub fn console_getchar() -> Option<u8> {
+    let ret = sbi_call(SBI_CONSOLE_GET_CHAR, 0, 0, 0) as isize;
+    (ret >= 0).then_some(ret as u8)
 }
 
 /// 调用 SBI_SHUTDOWN 来关闭操作系统（直接退出 QEMU）
@@ -68,11 +69,23 @@ pub const EXTENSION_RFENCE: usize = 0x52464E43;
 pub const EXTENSION_HSM: usize = 0x48534D;
 pub const EXTENSION_SRST: usize = 0x53525354;
 
+const FUNCTION_BASE_PROBE_EXTENSION: usize = 0x3;
+
+const FUNCTION_TIMER_SET_TIMER: usize = 0x0;
+
 const FUNCTION_HSM_HART_START: usize = 0x0;
 const FUNCTION_HSM_HART_STOP: usize = 0x1;
 const FUNCTION_HSM_HART_GET_STATUS: usize = 0x2;
 const FUNCTION_HSM_HART_SUSPEND: usize = 0x3;
 
+const FUNCTION_SRST_SYSTEM_RESET: usize = 0x0;
+
+pub const RESET_TYPE_SHUTDOWN: u32 = 0x0;
+pub const RESET_TYPE_COLD_REBOOT: u32 = 0x1;
+pub const RESET_TYPE_WARM_REBOOT: u32 = 0x2;
+
+pub const RESET_REASON_NO_REASON: u32 = 0x0;
+
 #[inline(always)]
 fn sbi_call_3(extension: usize, function: usize, arg0: usize, arg1: usize, arg2: usize) -> SbiRet {
     let (error, value);
@@ -87,6 +100,17 @@ fn sbi_call_3(extension: usize, function: usize, arg0: usize, arg1: usize, arg2:
     SbiRet { error, value }
 }
 
+/// 探测 SBI 扩展是否可用
+pub fn probe_extension(extension: usize) -> bool {
+    let ret = sbi_call_3(EXTENSION_BASE, FUNCTION_BASE_PROBE_EXTENSION, extension, 0, 0);
+    ret.error == 0 && ret.value != 0
+}
+
+/// 通过 TIME 扩展设置定时器
+pub fn set_timer_ext(stime_value: usize) -> SbiRet {
+    sbi_call_3(EXTENSION_TIMER, FUNCTION_TIMER_SET_TIMER, stime_value, 0, 0)
+}
+
 pub fn hart_suspend(suspend_type: u32, resume_addr: usize, opaque: usize) -> SbiRet {
     sbi_call_3(
         EXTENSION_HSM,
@@ -96,3 +120,14 @@ pub fn hart_suspend(suspend_type: u32, resume_addr: usize, opaque: usize) -> Sbi
         opaque,
     )
 }
+
+/// 通过 SRST 扩展关机或重启，成功时不会返回
+pub fn system_reset(reset_type: u32, reset_reason: u32) -> SbiRet {
+    sbi_call_3(
+        EXTENSION_SRST,
+        FUNCTION_SRST_SYSTEM_RESET,
+        reset_type as usize,
+        reset_reason as usize,
+        0,
+    )
+}
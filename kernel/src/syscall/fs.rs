@@ -0,0 +1,157 @@
+//! 文件相关的系统调用，目前只有标准输入输出，都连接到控制台终端
+
+use super::{
+    check_user_writable, read_user, read_user_bytes, write_user, write_user_bytes, Errno,
+    SyscallResult,
+};
+use crate::tty::{self, Mode};
+
+const STDIN: usize = 0;
+const STDOUT: usize = 1;
+const STDERR: usize = 2;
+
+/// 一次 read 最多读取的字节数
+const READ_MAX: usize = 4096;
+
+const TCGETS: usize = 0x5401;
+const TCSETS: usize = 0x5402;
+const TCSETSW: usize = 0x5403;
+const TCSETSF: usize = 0x5404;
+const TIOCGWINSZ: usize = 0x5413;
+
+/// termios 中 c_lflag 的标志位
+const ISIG: u32 = 0o1;
+const ICANON: u32 = 0o2;
+const ECHO: u32 = 0o10;
+
+/// 一次 writev 最多的 iovec 数量
+const IOV_MAX: usize = 1024;
+
+/// writev 使用的缓冲区描述
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct IoVec {
+    base: usize,
+    len: usize,
+}
+
+/// 从终端读取，规范模式下最多读取一行
+///
+/// 读取之前先检查 buf，buf 不合法时输入留在终端中
+pub fn sys_read(fd: usize, buf: usize, len: usize) -> SyscallResult {
+    if fd != STDIN {
+        return Err(Errno::EBADF);
+    }
+    if len == 0 {
+        return Ok(0);
+    }
+    let len = len.min(READ_MAX);
+    check_user_writable(buf, len)?;
+    let mut data = vec![0; len];
+    let read = tty::read(&mut data).map_err(|_| Errno::EINTR)?;
+    write_user_bytes(buf, &data[..read])?;
+    Ok(read)
+}
+
+pub fn sys_write(fd: usize, buf: usize, len: usize) -> SyscallResult {
+    if fd != STDOUT && fd != STDERR {
+        return Err(Errno::EBADF);
+    }
+    let data = read_user_bytes(buf, len)?;
+    crate::put_bytes(&data);
+    Ok(len)
+}
+
+pub fn sys_writev(fd: usize, iov: usize, iovcnt: usize) -> SyscallResult {
+    if iovcnt > IOV_MAX {
+        return Err(Errno::EINVAL);
+    }
+    let mut written = 0;
+    for i in 0..iovcnt {
+        let vec: IoVec = read_user(iov + i * core::mem::size_of::<IoVec>())?;
+        written += sys_write(fd, vec.base, vec.len)?;
+    }
+    Ok(written)
+}
+
+/// 内核中的 struct termios
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Termios {
+    iflag: u32,
+    oflag: u32,
+    cflag: u32,
+    lflag: u32,
+    line: u8,
+    cc: [u8; 19],
+}
+
+impl Termios {
+    /// 终端当前的设置，只有 ICANON 和 ECHO 是真实的状态
+    fn current() -> Self {
+        let mut lflag = ISIG;
+        if tty::mode() == Mode::Canonical {
+            lflag |= ICANON;
+        }
+        if tty::echo() {
+            lflag |= ECHO;
+        }
+        let mut cc = [0; 19];
+        // VINTR, VERASE, VKILL, VMIN
+        cc[0] = 0x03;
+        cc[2] = 0x7f;
+        cc[3] = 0x15;
+        cc[6] = 1;
+        Self {
+            // ICRNL
+            iflag: 0o400,
+            // OPOST | ONLCR
+            oflag: 0o5,
+            // B38400 | CS8 | CREAD
+            cflag: 0o277,
+            lflag,
+            line: 0,
+            cc,
+        }
+    }
+}
+
+/// 终端窗口大小
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct WinSize {
+    row: u16,
+    col: u16,
+    xpixel: u16,
+    ypixel: u16,
+}
+
+/// 标准输入输出都是控制台终端，支持读取和设置规范模式与回显
+pub fn sys_ioctl(fd: usize, request: usize, arg: usize) -> SyscallResult {
+    if fd != STDIN && fd != STDOUT && fd != STDERR {
+        return Err(Errno::EBADF);
+    }
+    match request {
+        TCGETS => write_user(arg, &Termios::current())?,
+        TCSETS | TCSETSW | TCSETSF => {
+            let termios: Termios = read_user(arg)?;
+            tty::set_mode(if termios.lflag & ICANON != 0 {
+                Mode::Canonical
+            } else {
+                Mode::Raw
+            });
+            tty::set_echo(termios.lflag & ECHO != 0);
+        }
+        TIOCGWINSZ => write_user(
+            arg,
+            &WinSize {
+                row: 24,
+                col: 80,
+                xpixel: 0,
+                ypixel: 0,
+            },
+        )?,
+        _ => return Err(Errno::ENOTTY),
+    }
+    Ok(0)
+}
@@ -0,0 +1,143 @@
+//! 内存管理相关的系统调用
+
+use super::{Errno, SyscallResult};
+use crate::memory_set::Backing;
+use crate::page_table::{PTEFlags, PAGE_SIZE};
+use crate::process::{current_process, USER_STACK_SIZE, USER_STACK_TOP};
+
+const PROT_READ: usize = 1;
+const PROT_WRITE: usize = 2;
+const PROT_EXEC: usize = 4;
+
+const MAP_SHARED: usize = 0x01;
+const MAP_PRIVATE: usize = 0x02;
+const MAP_TYPE: usize = MAP_SHARED | MAP_PRIVATE;
+const MAP_FIXED: usize = 0x10;
+const MAP_ANONYMOUS: usize = 0x20;
+
+/// 不指定地址时从这里开始查找空闲地址
+const MMAP_BASE: usize = 0x10_0000_0000;
+
+/// 用户栈的最低地址，mmap 不能超过它
+const MMAP_END: usize = USER_STACK_TOP - USER_STACK_SIZE;
+
+const fn page_ceil(addr: usize) -> usize {
+    (addr + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE
+}
+
+/// 用户传入的范围 [addr, addr + len) 按页对齐后的结束地址，超出 MMAP_END 时返回 None
+fn range_end(addr: usize, len: usize) -> Option<usize> {
+    len.checked_add(PAGE_SIZE - 1)
+        .and_then(|len| addr.checked_add(len / PAGE_SIZE * PAGE_SIZE))
+        .filter(|end| *end <= MMAP_END)
+}
+
+/// mmap 的权限转换为页表项标志位
+fn prot_to_flags(prot: usize) -> PTEFlags {
+    let mut flags = PTEFlags::U;
+    if prot & PROT_READ != 0 {
+        flags |= PTEFlags::R;
+    }
+    if prot & PROT_WRITE != 0 {
+        flags |= PTEFlags::R | PTEFlags::W;
+    }
+    if prot & PROT_EXEC != 0 {
+        flags |= PTEFlags::X;
+    }
+    flags
+}
+
+/// 设置 program break，失败时返回原来的值
+pub fn sys_brk(addr: usize) -> SyscallResult {
+    let process = current_process().ok_or(Errno::ENOMEM)?;
+    let mut memory_set = process.memory_set();
+    let old = memory_set.brk;
+    if addr < memory_set.heap_start || addr > MMAP_BASE {
+        return Ok(old);
+    }
+    let (old_end, new_end) = (page_ceil(old), page_ceil(addr));
+    let result = if new_end > old_end {
+        memory_set.map(
+            old_end,
+            new_end,
+            PTEFlags::U | PTEFlags::R | PTEFlags::W,
+            Backing::Anonymous,
+            false,
+        )
+    } else {
+        memory_set.unmap(new_end, old_end)
+    };
+    if result.is_ok() {
+        memory_set.brk = addr;
+    }
+    Ok(memory_set.brk)
+}
+
+/// 只支持匿名映射，私有映射的页帧在第一次访问时分配
+pub fn sys_mmap(
+    addr: usize,
+    len: usize,
+    prot: usize,
+    flags: usize,
+    fd: i32,
+    offset: usize,
+) -> SyscallResult {
+    if len == 0 || addr % PAGE_SIZE != 0 || offset % PAGE_SIZE != 0 {
+        return Err(Errno::EINVAL);
+    }
+    if flags & MAP_ANONYMOUS == 0 || fd != -1 {
+        return Err(Errno::ENODEV);
+    }
+    let shared = match flags & MAP_TYPE {
+        MAP_SHARED => true,
+        MAP_PRIVATE => false,
+        _ => return Err(Errno::EINVAL),
+    };
+    let len = len.checked_add(PAGE_SIZE - 1).ok_or(Errno::ENOMEM)? / PAGE_SIZE * PAGE_SIZE;
+    let process = current_process().ok_or(Errno::ENOMEM)?;
+    let mut memory_set = process.memory_set();
+
+    let fits = addr.checked_add(len).map_or(false, |end| end <= MMAP_END);
+    let start = if flags & MAP_FIXED != 0 {
+        if !fits {
+            return Err(Errno::ENOMEM);
+        }
+        memory_set.unmap(addr, addr + len)?;
+        addr
+    } else if addr != 0 && fits && !memory_set.overlaps(addr, addr + len) {
+        addr
+    } else {
+        memory_set
+            .find_free(MMAP_BASE, MMAP_END, len)
+            .ok_or(Errno::ENOMEM)?
+    };
+    memory_set.map(start, start + len, prot_to_flags(prot), Backing::Anonymous, shared)?;
+    Ok(start)
+}
+
+pub fn sys_munmap(addr: usize, len: usize) -> SyscallResult {
+    if addr % PAGE_SIZE != 0 || len == 0 {
+        return Err(Errno::EINVAL);
+    }
+    let end = range_end(addr, len).ok_or(Errno::EINVAL)?;
+    let process = current_process().ok_or(Errno::EINVAL)?;
+    process.memory_set().unmap(addr, end)?;
+    Ok(0)
+}
+
+/// 范围中有没有映射的地址时返回 ENOMEM
+pub fn sys_mprotect(addr: usize, len: usize, prot: usize) -> SyscallResult {
+    if addr % PAGE_SIZE != 0 || prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+        return Err(Errno::EINVAL);
+    }
+    if len == 0 {
+        return Ok(0);
+    }
+    let end = range_end(addr, len).ok_or(Errno::ENOMEM)?;
+    let process = current_process().ok_or(Errno::ENOMEM)?;
+    process
+        .memory_set()
+        .protect(addr, end, prot_to_flags(prot))
+        .map_err(|_| Errno::ENOMEM)?;
+    Ok(0)
+}
@@ -0,0 +1,199 @@
+//! 系统调用
+//!
+//! 用户态通过 ecall 进入内核，a7 为系统调用号，a0 - a5 为参数，返回值放回 a0。
+//! 调用号和参数与 Linux RISC-V 相同，出错时返回负的错误码。
+//! 访问用户内存前都会通过进程页表检查地址和权限，不合法时返回 -EFAULT。
+
+mod fs;
+mod mm;
+mod process;
+mod system;
+mod time;
+
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::mem::{size_of, MaybeUninit};
+use log::warn;
+
+use crate::memory_set::AccessType;
+use crate::page_table::{PagingError, PAGE_SIZE};
+use crate::process::current_process;
+use crate::trap::TrapFrame;
+
+const SYSCALL_IOCTL: usize = 29;
+const SYSCALL_READ: usize = 63;
+const SYSCALL_WRITE: usize = 64;
+const SYSCALL_WRITEV: usize = 66;
+const SYSCALL_EXIT: usize = 93;
+const SYSCALL_EXIT_GROUP: usize = 94;
+const SYSCALL_SET_TID_ADDRESS: usize = 96;
+const SYSCALL_NANOSLEEP: usize = 101;
+const SYSCALL_CLOCK_GETTIME: usize = 113;
+const SYSCALL_UNAME: usize = 160;
+const SYSCALL_GETPID: usize = 172;
+const SYSCALL_GETPPID: usize = 173;
+const SYSCALL_BRK: usize = 214;
+const SYSCALL_MUNMAP: usize = 215;
+const SYSCALL_CLONE: usize = 220;
+const SYSCALL_EXECVE: usize = 221;
+const SYSCALL_MMAP: usize = 222;
+const SYSCALL_MPROTECT: usize = 226;
+const SYSCALL_WAIT4: usize = 260;
+
+/// 路径的最大长度，包括结尾的 0
+const PATH_MAX: usize = 4096;
+
+/// 错误码，名称和 Linux 保持一致
+#[allow(clippy::upper_case_acronyms)]
+#[repr(isize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Errno {
+    ENOENT = 2,
+    EINTR = 4,
+    E2BIG = 7,
+    ENOEXEC = 8,
+    EBADF = 9,
+    ECHILD = 10,
+    ENOMEM = 12,
+    EFAULT = 14,
+    ENODEV = 19,
+    EINVAL = 22,
+    ENOTTY = 25,
+    ENAMETOOLONG = 36,
+    ENOSYS = 38,
+}
+
+impl From<PagingError> for Errno {
+    fn from(err: PagingError) -> Self {
+        match err {
+            PagingError::NoMemory => Errno::ENOMEM,
+            _ => Errno::EFAULT,
+        }
+    }
+}
+
+pub type SyscallResult = Result<usize, Errno>;
+
+/// 从用户地址 vaddr 读取 len 字节
+fn read_user_bytes(vaddr: usize, len: usize) -> Result<Vec<u8>, Errno> {
+    let process = current_process().ok_or(Errno::EFAULT)?;
+    let data = process.memory_set().read_user(vaddr, len)?;
+    Ok(data)
+}
+
+/// 把 data 写入用户地址 vaddr
+fn write_user_bytes(vaddr: usize, data: &[u8]) -> Result<(), Errno> {
+    let process = current_process().ok_or(Errno::EFAULT)?;
+    process.memory_set().write_user(vaddr, data)?;
+    Ok(())
+}
+
+/// 检查用户地址 [vaddr, vaddr + len) 可写，用于在产生数据之前检查输出缓冲区
+fn check_user_writable(vaddr: usize, len: usize) -> Result<(), Errno> {
+    let process = current_process().ok_or(Errno::EFAULT)?;
+    process.memory_set().fault_in(vaddr, len, AccessType::Write)?;
+    Ok(())
+}
+
+/// 从用户地址 vaddr 读取以 0 结尾的字符串，包括结尾的 0 最多 max_len 字节
+///
+/// 不是合法 UTF-8 的字节会被替换
+fn read_user_str(vaddr: usize, max_len: usize) -> Result<String, Errno> {
+    let process = current_process().ok_or(Errno::EFAULT)?;
+    let mut memory_set = process.memory_set();
+    let mut data = Vec::new();
+    let mut current = vaddr;
+    while data.len() < max_len {
+        // 每次最多读到页的结尾，避免访问字符串后面没有映射的页
+        let len = (PAGE_SIZE - current % PAGE_SIZE).min(max_len - data.len());
+        let chunk = memory_set.read_user(current, len)?;
+        if let Some(end) = chunk.iter().position(|byte| *byte == 0) {
+            data.extend_from_slice(&chunk[..end]);
+            return Ok(String::from_utf8_lossy(&data).to_string());
+        }
+        data.extend_from_slice(&chunk);
+        current += len;
+    }
+    Err(Errno::ENAMETOOLONG)
+}
+
+/// 读取用户态以空指针结尾的字符串指针数组，最多 max_count 项，vaddr 为 0 时为空数组
+fn read_user_str_array(vaddr: usize, max_count: usize) -> Result<Vec<String>, Errno> {
+    let mut strings = Vec::new();
+    if vaddr == 0 {
+        return Ok(strings);
+    }
+    for index in 0.. {
+        let ptr = vaddr
+            .checked_add(index * size_of::<usize>())
+            .ok_or(Errno::EFAULT)?;
+        let str_ptr: usize = read_user(ptr)?;
+        if str_ptr == 0 {
+            break;
+        }
+        if strings.len() == max_count {
+            return Err(Errno::E2BIG);
+        }
+        strings.push(read_user_str(str_ptr, PATH_MAX).map_err(|errno| match errno {
+            Errno::ENAMETOOLONG => Errno::E2BIG,
+            errno => errno,
+        })?);
+    }
+    Ok(strings)
+}
+
+/// 从用户地址 vaddr 读取一个 T
+///
+/// T 必须是任意字节都合法的类型
+fn read_user<T: Copy>(vaddr: usize) -> Result<T, Errno> {
+    let bytes = read_user_bytes(vaddr, size_of::<T>())?;
+    let mut value = MaybeUninit::<T>::uninit();
+    unsafe {
+        core::ptr::copy_nonoverlapping(bytes.as_ptr(), value.as_mut_ptr() as *mut u8, bytes.len());
+        Ok(value.assume_init())
+    }
+}
+
+/// 把 value 写入用户地址 vaddr
+fn write_user<T: Copy>(vaddr: usize, value: &T) -> Result<(), Errno> {
+    let bytes = unsafe { core::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) };
+    write_user_bytes(vaddr, bytes)
+}
+
+/// 系统调用分发，参数和返回值通过 tf 传递
+pub fn syscall(tf: &mut TrapFrame) {
+    let id = tf.x[17];
+    let args = [tf.x[10], tf.x[11], tf.x[12], tf.x[13], tf.x[14], tf.x[15]];
+    let result = match id {
+        SYSCALL_IOCTL => fs::sys_ioctl(args[0], args[1], args[2]),
+        SYSCALL_READ => fs::sys_read(args[0], args[1], args[2]),
+        SYSCALL_WRITE => fs::sys_write(args[0], args[1], args[2]),
+        SYSCALL_WRITEV => fs::sys_writev(args[0], args[1], args[2]),
+        SYSCALL_EXIT => process::sys_exit(args[0] as i32),
+        SYSCALL_EXIT_GROUP => process::sys_exit_group(args[0] as i32),
+        SYSCALL_SET_TID_ADDRESS => process::sys_set_tid_address(args[0]),
+        SYSCALL_NANOSLEEP => time::sys_nanosleep(args[0], args[1]),
+        SYSCALL_CLOCK_GETTIME => time::sys_clock_gettime(args[0], args[1]),
+        SYSCALL_UNAME => system::sys_uname(args[0]),
+        SYSCALL_GETPID => process::sys_getpid(),
+        SYSCALL_GETPPID => process::sys_getppid(),
+        SYSCALL_BRK => mm::sys_brk(args[0]),
+        SYSCALL_MUNMAP => mm::sys_munmap(args[0], args[1]),
+        SYSCALL_CLONE => process::sys_clone(tf, args[0], args[1]),
+        SYSCALL_EXECVE => process::sys_execve(tf, args[0], args[1], args[2]),
+        SYSCALL_MMAP => mm::sys_mmap(args[0], args[1], args[2], args[3], args[4] as i32, args[5]),
+        SYSCALL_MPROTECT => mm::sys_mprotect(args[0], args[1], args[2]),
+        SYSCALL_WAIT4 => process::sys_wait4(args[0] as isize, args[1], args[2]),
+        _ => {
+            warn!("unsupported syscall {}", id);
+            Err(Errno::ENOSYS)
+        }
+    };
+    let ret = match result {
+        Ok(value) => value as isize,
+        Err(errno) => -(errno as isize),
+    };
+    tf.x[10] = ret as usize;
+}
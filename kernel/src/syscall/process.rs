@@ -0,0 +1,80 @@
+//! 进程相关的系统调用
+
+use alloc::vec::Vec;
+
+use super::{read_user_str, read_user_str_array, write_user, Errno, SyscallResult, PATH_MAX};
+use crate::process::{self, current_process, exit, find_program};
+use crate::trap::TrapFrame;
+
+const CLONE_VM: usize = 0x100;
+const CLONE_VFORK: usize = 0x4000;
+const CLONE_THREAD: usize = 0x10000;
+
+/// 低 8 位是子进程退出时发送给父进程的信号，忽略
+const CLONE_SIGNAL_MASK: usize = 0xff;
+
+const WNOHANG: usize = 1;
+
+/// argv 和 envp 的最大项数
+const ARG_MAX: usize = 256;
+
+pub fn sys_exit(code: i32) -> SyscallResult {
+    exit(code)
+}
+
+/// 每个进程只有一个线程，和 exit 相同
+pub fn sys_exit_group(code: i32) -> SyscallResult {
+    exit(code)
+}
+
+pub fn sys_getpid() -> SyscallResult {
+    current_process().map(|process| process.pid()).ok_or(Errno::EINVAL)
+}
+
+/// 没有父进程时返回 0
+pub fn sys_getppid() -> SyscallResult {
+    let process = current_process().ok_or(Errno::EINVAL)?;
+    Ok(process.parent().map_or(0, |parent| parent.pid()))
+}
+
+/// 还不支持线程退出时清除 tid，直接返回线程 id
+pub fn sys_set_tid_address(_tidptr: usize) -> SyscallResult {
+    sys_getpid()
+}
+
+/// 只支持 fork，CLONE_VM | CLONE_VFORK (vfork) 也按 fork 处理
+pub fn sys_clone(tf: &TrapFrame, flags: usize, stack: usize) -> SyscallResult {
+    let flags = flags & !CLONE_SIGNAL_MASK;
+    if flags & CLONE_THREAD != 0 || (flags & CLONE_VM != 0 && flags & CLONE_VFORK == 0) {
+        return Err(Errno::EINVAL);
+    }
+    if flags & !(CLONE_VM | CLONE_VFORK) != 0 {
+        return Err(Errno::EINVAL);
+    }
+    Ok(process::fork(tf, stack)?)
+}
+
+/// 程序从编译时嵌入内核的程序中按文件名查找，成功时不返回到原来的程序
+pub fn sys_execve(tf: &mut TrapFrame, path: usize, argv: usize, envp: usize) -> SyscallResult {
+    let path = read_user_str(path, PATH_MAX)?;
+    let argv = read_user_str_array(argv, ARG_MAX)?;
+    let envp = read_user_str_array(envp, ARG_MAX)?;
+    let data = find_program(&path).ok_or(Errno::ENOENT)?;
+    let name = path.rsplit('/').next().unwrap_or(&path);
+    let argv: Vec<&str> = argv.iter().map(|arg| arg.as_str()).collect();
+    let envp: Vec<&str> = envp.iter().map(|env| env.as_str()).collect();
+    process::exec(tf, name, data, &argv, &envp).map_err(|_| Errno::ENOEXEC)?;
+    Ok(0)
+}
+
+/// pid 小于等于 0 时等待任意子进程，不支持进程组
+pub fn sys_wait4(pid: isize, wstatus: usize, options: usize) -> SyscallResult {
+    if options & !WNOHANG != 0 {
+        return Err(Errno::EINVAL);
+    }
+    let (pid, status) = process::wait_child(pid, options & WNOHANG != 0).ok_or(Errno::ECHILD)?;
+    if pid != 0 && wstatus != 0 {
+        write_user(wstatus, &status)?;
+    }
+    Ok(pid)
+}
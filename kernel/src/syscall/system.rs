@@ -0,0 +1,39 @@
+//! 系统信息相关的系统调用
+
+use super::{write_user, SyscallResult};
+
+/// struct utsname 每个字段的长度
+const UTSNAME_LEN: usize = 65;
+
+/// struct utsname
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct UtsName {
+    sysname: [u8; UTSNAME_LEN],
+    nodename: [u8; UTSNAME_LEN],
+    release: [u8; UTSNAME_LEN],
+    version: [u8; UTSNAME_LEN],
+    machine: [u8; UTSNAME_LEN],
+    domainname: [u8; UTSNAME_LEN],
+}
+
+/// 转换为以 0 结尾的定长字符串
+fn field(s: &str) -> [u8; UTSNAME_LEN] {
+    let mut field = [0; UTSNAME_LEN];
+    let len = s.len().min(UTSNAME_LEN - 1);
+    field[..len].copy_from_slice(&s.as_bytes()[..len]);
+    field
+}
+
+pub fn sys_uname(buf: usize) -> SyscallResult {
+    let uts = UtsName {
+        sysname: field("rv64os"),
+        nodename: field("localhost"),
+        release: field(env!("CARGO_PKG_VERSION")),
+        version: field("#1"),
+        machine: field("riscv64"),
+        domainname: field(""),
+    };
+    write_user(buf, &uts)?;
+    Ok(0)
+}
@@ -0,0 +1,50 @@
+//! 时间相关的系统调用
+
+use super::{read_user, write_user, Errno, SyscallResult};
+use crate::{clock, timer};
+
+const CLOCK_REALTIME: usize = 0;
+const CLOCK_MONOTONIC: usize = 1;
+
+const NSEC_PER_SEC: usize = 1_000_000_000;
+
+/// struct timespec
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct TimeSpec {
+    sec: usize,
+    nsec: usize,
+}
+
+/// CLOCK_REALTIME 是自 1970 年以来的时间，CLOCK_MONOTONIC 是启动以来的时间
+pub fn sys_clock_gettime(clock_id: usize, tp: usize) -> SyscallResult {
+    let now = match clock_id {
+        CLOCK_REALTIME => clock::realtime(),
+        CLOCK_MONOTONIC => clock::monotonic(),
+        _ => return Err(Errno::EINVAL),
+    };
+    let time = TimeSpec {
+        sec: now.as_secs() as usize,
+        nsec: now.subsec_nanos() as usize,
+    };
+    write_user(tp, &time)?;
+    Ok(0)
+}
+
+/// 按 tick 精度睡眠，时间向上取整
+pub fn sys_nanosleep(req: usize, rem: usize) -> SyscallResult {
+    let req: TimeSpec = read_user(req)?;
+    if req.nsec >= NSEC_PER_SEC || (req.sec as isize) < 0 {
+        return Err(Errno::EINVAL);
+    }
+    let hz = timer::ticks_per_sec() as u128;
+    let ns = req.sec as u128 * NSEC_PER_SEC as u128 + req.nsec as u128;
+    let ticks = (ns * hz + NSEC_PER_SEC as u128 - 1) / NSEC_PER_SEC as u128;
+    // 超出 usize 的时间相当于一直睡眠
+    let ticks = usize::try_from(ticks).unwrap_or(usize::MAX);
+    timer::sleep_until(timer::ticks().saturating_add(ticks));
+    if rem != 0 {
+        write_user(rem, &TimeSpec::default())?;
+    }
+    Ok(0)
+}
@@ -0,0 +1,53 @@
+use core::arch::asm;
+
+/// 任务切换时保存的上下文
+///
+/// 只需要保存被调用者保存的寄存器，其余寄存器由调用 context_switch 的函数负责
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TaskContext {
+    /// 返回地址，新任务的入口
+    pub ra: usize,
+    /// 栈指针
+    pub sp: usize,
+    /// s0 - s11
+    pub s: [usize; 12],
+}
+
+impl TaskContext {
+    /// 创建一个从 entry 开始执行，使用 stack_top 作为栈顶的上下文
+    pub const fn new(entry: usize, stack_top: usize) -> Self {
+        Self {
+            ra: entry,
+            sp: stack_top,
+            s: [0; 12],
+        }
+    }
+}
+
+/// 保存当前上下文到 from，切换到 to
+///
+/// 再次切换回 from 时从这里返回
+#[naked]
+pub unsafe extern "C" fn context_switch(from: *mut TaskContext, to: *const TaskContext) {
+    asm!(
+        // 保存当前任务的上下文
+        "
+            sd      ra, 0*8(a0)
+            sd      sp, 1*8(a0)
+            .irp n, 0,1,2,3,4,5,6,7,8,9,10,11
+            sd      s\\n, (\\n+2)*8(a0)
+            .endr
+        ",
+        // 恢复下一个任务的上下文
+        "
+            ld      ra, 0*8(a1)
+            ld      sp, 1*8(a1)
+            .irp n, 0,1,2,3,4,5,6,7,8,9,10,11
+            ld      s\\n, (\\n+2)*8(a1)
+            .endr
+            ret
+        ",
+        options(noreturn)
+    )
+}
@@ -0,0 +1,513 @@
+//! 内核线程
+//!
+//! 每个内核线程有自己的内核栈。就绪的线程按优先级类别分别排队，同一类别内轮转，
+//! 时钟中断中扣减当前任务的时间片，用完后或有更高优先级的任务就绪时抢占当前任务。
+//! 没有就绪任务时运行 idle 任务等待中断。
+//!
+//! 调度器的锁也会在时钟中断中使用，所以持有这些锁时必须关闭中断。
+
+mod context;
+mod scheduler;
+mod wait_queue;
+
+use alloc::{
+    boxed::Box,
+    format,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use core::{
+    arch::asm,
+    cell::UnsafeCell,
+    mem,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+use log::{debug, info};
+use spin::Mutex;
+
+use crate::frame::{frame_alloc_contiguous, FrameError, TrackerFrames};
+use crate::page_table::{kernel_satp, paddr_to_virt, switch_satp, PAGE_SIZE};
+use crate::process::Process;
+use crate::sbi::{self, EXTENSION_HSM};
+use crate::timer;
+use crate::trap::{enable_interrupts, enter_user, without_interrupts, TrapFrame};
+
+pub use context::{context_switch, TaskContext};
+pub use scheduler::Priority;
+pub use wait_queue::WaitQueue;
+
+use scheduler::RunQueue;
+
+/// 内核栈大小
+const KERNEL_STACK_SIZE: usize = 0x1_0000;
+
+/// 任务状态
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskState {
+    /// 在就绪队列中等待运行
+    Ready,
+    /// 正在运行
+    Running,
+    /// 睡眠到 tick 数到达指定值
+    Sleeping(usize),
+    /// 在等待队列中等待被唤醒
+    Blocked,
+    /// 已经退出，等待回收
+    Exited,
+}
+
+/// 内核线程
+pub struct Task {
+    id: usize,
+    name: String,
+    priority: Priority,
+    state: Mutex<TaskState>,
+    /// 切换时保存的上下文，只在切换任务时访问
+    context: UnsafeCell<TaskContext>,
+    /// 内核栈，启动任务使用 main.rs 中的 STACK
+    stack: Option<TrackerFrames>,
+    /// 线程入口，第一次运行时取出
+    entry: Mutex<Option<Box<dyn FnOnce() + Send>>>,
+    /// 所属的用户进程，内核线程为 None
+    process: Option<Arc<Process>>,
+    /// 剩余的时间片 (tick 数)
+    time_slice: AtomicUsize,
+    /// 累计运行时间 (time 寄存器的计数)
+    cpu_time: AtomicUsize,
+    /// 最近一次开始运行时 time 寄存器的值
+    last_run: AtomicUsize,
+    /// 被调度运行的次数
+    switches: AtomicUsize,
+    /// 在进入 Blocked 状态之前被唤醒
+    woken: AtomicBool,
+}
+
+// context 只在切换任务时访问，同一时间只有一个 CPU 在切换
+unsafe impl Sync for Task {}
+
+impl Task {
+    fn new(
+        name: &str,
+        priority: Priority,
+        state: TaskState,
+        context: TaskContext,
+        stack: Option<TrackerFrames>,
+        entry: Option<Box<dyn FnOnce() + Send>>,
+        process: Option<Arc<Process>>,
+    ) -> Self {
+        Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            name: name.to_string(),
+            priority,
+            state: Mutex::new(state),
+            context: UnsafeCell::new(context),
+            stack,
+            entry: Mutex::new(entry),
+            process,
+            time_slice: AtomicUsize::new(priority.time_slice()),
+            cpu_time: AtomicUsize::new(0),
+            last_run: AtomicUsize::new(timer::get_time()),
+            switches: AtomicUsize::new(0),
+            woken: AtomicBool::new(false),
+        }
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    pub fn state(&self) -> TaskState {
+        without_interrupts(|| *self.state.lock())
+    }
+
+    pub fn process(&self) -> Option<&Arc<Process>> {
+        self.process.as_ref()
+    }
+
+    /// 运行时使用的页表
+    fn satp(&self) -> usize {
+        self.process.as_ref().map_or_else(kernel_satp, |process| process.satp())
+    }
+
+    /// 内核栈的地址范围
+    pub fn stack_range(&self) -> Option<(usize, usize)> {
+        self.stack.as_ref().map(|stack| {
+            let start = paddr_to_virt(stack.start);
+            (start, start + stack.count * PAGE_SIZE)
+        })
+    }
+
+    /// 累计运行时间 (微秒)，正在运行的任务包括本次运行的时间
+    pub fn cpu_time_us(&self) -> usize {
+        let mut time = self.cpu_time.load(Ordering::Relaxed);
+        if self.state() == TaskState::Running {
+            time += timer::get_time() - self.last_run.load(Ordering::Relaxed);
+        }
+        match timer::timebase_freq() {
+            0 => 0,
+            freq => (time as u128 * 1_000_000 / freq as u128) as usize,
+        }
+    }
+}
+
+/// 下一个任务 id
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// 就绪队列
+static RUN_QUEUE: Mutex<RunQueue> = Mutex::new(RunQueue::new());
+
+/// 当前正在运行的任务
+static CURRENT: Mutex<Option<Arc<Task>>> = Mutex::new(None);
+
+/// 没有就绪任务时运行的任务
+static IDLE_TASK: Mutex<Option<Arc<Task>>> = Mutex::new(None);
+
+/// 正在睡眠的任务
+static SLEEPING: Mutex<Vec<Arc<Task>>> = Mutex::new(Vec::new());
+
+/// 已经退出的任务，切换到下一个任务之后再释放它们的栈
+static EXITED: Mutex<Vec<Arc<Task>>> = Mutex::new(Vec::new());
+
+/// 还没有退出的任务数量，不包括 idle 任务
+static TASK_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// SBI 是否支持 HSM 扩展，支持时 idle 任务通过 hart_suspend 等待中断
+static HAS_HSM_EXT: AtomicBool = AtomicBool::new(false);
+
+/// 把当前的启动流程作为第一个任务，并创建 idle 任务
+pub fn init() {
+    HAS_HSM_EXT.store(sbi::probe_extension(EXTENSION_HSM), Ordering::Relaxed);
+    let main = Arc::new(Task::new(
+        "main",
+        Priority::Normal,
+        TaskState::Running,
+        TaskContext::default(),
+        None,
+        None,
+        None,
+    ));
+    let idle = new_task("idle", Priority::Idle, None, idle).expect("can't create idle task");
+    without_interrupts(|| {
+        *CURRENT.lock() = Some(main);
+        *IDLE_TASK.lock() = Some(idle);
+    });
+    TASK_COUNT.fetch_add(1, Ordering::Relaxed);
+}
+
+/// 当前任务
+pub fn current() -> Option<Arc<Task>> {
+    without_interrupts(|| CURRENT.lock().clone())
+}
+
+/// 就绪任务数量
+pub fn ready_count() -> usize {
+    without_interrupts(|| RUN_QUEUE.lock().len())
+}
+
+/// 还没有退出的任务数量，不包括 idle 任务
+pub fn task_count() -> usize {
+    TASK_COUNT.load(Ordering::Relaxed)
+}
+
+/// 创建运行 f 的任务，不加入就绪队列
+fn new_task<F>(
+    name: &str,
+    priority: Priority,
+    process: Option<Arc<Process>>,
+    f: F,
+) -> Result<Arc<Task>, FrameError>
+where
+    F: FnOnce() + Send + 'static,
+{
+    new_task_at(name, priority, process, Some(Box::new(f)), task_entry as usize, 0)
+}
+
+/// 创建任务，不加入就绪队列
+///
+/// 任务第一次运行时从 start 开始，内核栈顶保留 reserved 字节，栈从保留的空间下面开始
+fn new_task_at(
+    name: &str,
+    priority: Priority,
+    process: Option<Arc<Process>>,
+    entry: Option<Box<dyn FnOnce() + Send>>,
+    start: usize,
+    reserved: usize,
+) -> Result<Arc<Task>, FrameError> {
+    let stack = frame_alloc_contiguous(KERNEL_STACK_SIZE / PAGE_SIZE, PAGE_SIZE)?;
+    let stack_top = paddr_to_virt(stack.start) + KERNEL_STACK_SIZE;
+    let task = Arc::new(Task::new(
+        name,
+        priority,
+        TaskState::Ready,
+        TaskContext::new(start, stack_top - reserved),
+        Some(stack),
+        entry,
+        process,
+    ));
+    debug!(
+        "spawn task {} ({}, {:?}), stack: {:#x?}",
+        task.id,
+        name,
+        priority,
+        task.stack_range()
+    );
+    Ok(task)
+}
+
+/// 创建一个普通优先级的内核线程，返回任务 id
+pub fn spawn<F>(name: &str, f: F) -> Result<usize, FrameError>
+where
+    F: FnOnce() + Send + 'static,
+{
+    spawn_with_priority(name, Priority::Normal, f)
+}
+
+/// 创建一个指定优先级的内核线程，返回任务 id
+pub fn spawn_with_priority<F>(name: &str, priority: Priority, f: F) -> Result<usize, FrameError>
+where
+    F: FnOnce() + Send + 'static,
+{
+    assert!(priority != Priority::Idle, "only the idle task can use Priority::Idle");
+    let task = new_task(name, priority, None, f)?;
+    Ok(add_task(task))
+}
+
+/// 创建用户进程的任务，任务第一次运行时按照 tf 进入用户态
+///
+/// tf 放在内核栈顶，之后从用户态陷入时的 TrapFrame 也保存在这里
+pub fn spawn_user(name: &str, process: Arc<Process>, tf: TrapFrame) -> Result<usize, FrameError> {
+    let size = mem::size_of::<TrapFrame>();
+    let task = new_task_at(name, Priority::Normal, Some(process), None, user_task_entry as usize, size)?;
+    let (_, stack_top) = task.stack_range().expect("user task has no kernel stack");
+    unsafe { ((stack_top - size) as *mut TrapFrame).write(tf) };
+    Ok(add_task(task))
+}
+
+/// 把新任务加入就绪队列，返回任务 id
+fn add_task(task: Arc<Task>) -> usize {
+    let id = task.id;
+    TASK_COUNT.fetch_add(1, Ordering::Relaxed);
+    without_interrupts(|| RUN_QUEUE.lock().push(task));
+    id
+}
+
+/// 新任务第一次运行时从这里开始
+///
+/// 切换过来时中断是关闭的
+extern "C" fn task_entry() -> ! {
+    enable_interrupts();
+    drop_exited();
+    let entry = current()
+        .and_then(|task| task.entry.lock().take())
+        .expect("task has no entry");
+    entry();
+    exit()
+}
+
+/// 用户进程的任务第一次运行时从这里开始，内核栈顶是进入用户态的 TrapFrame
+///
+/// 切换过来时中断是关闭的
+extern "C" fn user_task_entry() -> ! {
+    enable_interrupts();
+    drop_exited();
+    let (_, stack_top) = current()
+        .and_then(|task| task.stack_range())
+        .expect("user task has no kernel stack");
+    enter_user(stack_top)
+}
+
+/// 没有就绪任务时运行，回收退出的任务并等待中断
+fn idle() {
+    loop {
+        drop_exited();
+        if ready_count() > 0 {
+            yield_now();
+            continue;
+        }
+        // 中断会在这里被处理，时钟中断发现有就绪任务时直接抢占 idle 任务
+        if HAS_HSM_EXT.load(Ordering::Relaxed) {
+            // 默认的保持状态挂起，和 wfi 一样在中断到来时返回
+            sbi::hart_suspend(0, 0, 0);
+        } else {
+            unsafe { asm!("wfi") };
+        }
+    }
+}
+
+/// 释放已经退出的任务
+///
+/// 释放内核栈需要页帧分配器的锁，只能在打开中断的任务上下文中调用
+fn drop_exited() {
+    let exited: Vec<_> = without_interrupts(|| EXITED.lock().drain(..).collect());
+    drop(exited);
+}
+
+/// 切换到下一个就绪任务，当前任务的状态变为 state
+///
+/// 当前任务仍然就绪时只切换到优先级不低于它的任务，没有这样的任务时直接返回。
+/// 调用时必须关闭中断
+fn schedule(state: TaskState) {
+    let priority = match CURRENT.lock().as_ref() {
+        Some(current) => current.priority,
+        None => panic!("schedule before task::init"),
+    };
+    let next = {
+        let mut run_queue = RUN_QUEUE.lock();
+        match run_queue.highest_priority() {
+            Some(highest) if state != TaskState::Ready || highest <= priority => run_queue.pop(),
+            _ => None,
+        }
+    };
+    let next = match next {
+        Some(next) => next,
+        None if state == TaskState::Ready => return,
+        None => IDLE_TASK.lock().clone().expect("idle task not created"),
+    };
+    let prev = match CURRENT.lock().replace(next.clone()) {
+        Some(prev) => prev,
+        None => panic!("schedule before task::init"),
+    };
+
+    let now = timer::get_time();
+    prev.cpu_time.fetch_add(
+        now - prev.last_run.load(Ordering::Relaxed),
+        Ordering::Relaxed,
+    );
+    next.last_run.store(now, Ordering::Relaxed);
+    next.time_slice.store(next.priority.time_slice(), Ordering::Relaxed);
+    next.switches.fetch_add(1, Ordering::Relaxed);
+    *prev.state.lock() = state;
+    *next.state.lock() = TaskState::Running;
+
+    let prev_context = prev.context.get();
+    let next_context = next.context.get();
+    switch_satp(next.satp());
+    match state {
+        TaskState::Exited => EXITED.lock().push(prev),
+        TaskState::Sleeping(_) => SLEEPING.lock().push(prev),
+        // 由等待队列持有
+        TaskState::Blocked => {}
+        _ => RUN_QUEUE.lock().push(prev),
+    }
+    drop(next);
+
+    unsafe { context_switch(prev_context, next_context) };
+}
+
+/// 唤醒一个在等待队列中的任务，调用时必须关闭中断
+///
+/// 任务还没有进入 Blocked 状态时只设置 woken，它不会再进入 Blocked 状态
+fn wake(task: Arc<Task>) {
+    let mut state = task.state.lock();
+    if *state == TaskState::Blocked {
+        *state = TaskState::Ready;
+        drop(state);
+        RUN_QUEUE.lock().push(task);
+    } else {
+        task.woken.store(true, Ordering::Relaxed);
+    }
+}
+
+/// 时钟中断中调用，唤醒睡眠到期的任务，检查是否需要抢占当前任务
+pub fn scheduler_tick() {
+    let current = match CURRENT.lock().clone() {
+        Some(current) => current,
+        None => return,
+    };
+
+    let now = timer::ticks();
+    let mut run_queue = RUN_QUEUE.lock();
+    SLEEPING.lock().retain(|task| {
+        let state = &mut *task.state.lock();
+        match *state {
+            TaskState::Sleeping(until) if until <= now => {
+                *state = TaskState::Ready;
+                run_queue.push(task.clone());
+                false
+            }
+            _ => true,
+        }
+    });
+
+    let slice_expired = current.time_slice.fetch_sub(1, Ordering::Relaxed) <= 1;
+    let preempt = match run_queue.highest_priority() {
+        Some(priority) => {
+            priority < current.priority || (slice_expired && priority == current.priority)
+        }
+        None => false,
+    };
+    drop(run_queue);
+    if slice_expired {
+        current
+            .time_slice
+            .store(current.priority.time_slice(), Ordering::Relaxed);
+    }
+    drop(current);
+
+    if preempt {
+        schedule(TaskState::Ready);
+    }
+}
+
+/// 让出 CPU，当前任务回到就绪队列末尾
+pub fn yield_now() {
+    if current().is_some() {
+        without_interrupts(|| schedule(TaskState::Ready));
+        drop_exited();
+    }
+}
+
+/// 当前任务睡眠到 tick 数到达 target
+pub fn sleep_until(target: usize) {
+    without_interrupts(|| {
+        if timer::ticks() < target {
+            schedule(TaskState::Sleeping(target));
+        }
+    });
+    drop_exited();
+}
+
+/// 结束当前任务
+pub fn exit() -> ! {
+    if let Some(task) = current() {
+        debug!("task {} ({}) exited", task.id, task.name);
+    }
+    TASK_COUNT.fetch_sub(1, Ordering::Relaxed);
+    without_interrupts(|| schedule(TaskState::Exited));
+    unreachable!("exited task was scheduled again");
+}
+
+/// 输出所有任务的运行时间统计
+pub fn dump_stats() {
+    let tasks: Vec<Arc<Task>> = without_interrupts(|| {
+        let mut tasks: Vec<_> = CURRENT.lock().iter().cloned().collect();
+        tasks.extend(RUN_QUEUE.lock().iter().cloned());
+        tasks.extend(SLEEPING.lock().iter().cloned());
+        tasks.extend(IDLE_TASK.lock().iter().cloned());
+        tasks
+    });
+    info!(
+        "{:>4} {:<12} {:<8} {:<16} {:>12} {:>8}",
+        "id", "name", "priority", "state", "cpu time(us)", "switches"
+    );
+    for task in tasks {
+        info!(
+            "{:>4} {:<12} {:<8} {:<16} {:>12} {:>8}",
+            task.id,
+            task.name,
+            format!("{:?}", task.priority),
+            format!("{:?}", task.state()),
+            task.cpu_time_us(),
+            task.switches.load(Ordering::Relaxed)
+        );
+    }
+}
@@ -0,0 +1,66 @@
+use alloc::{collections::VecDeque, sync::Arc};
+
+use super::Task;
+
+/// 优先级类别，高优先级的任务就绪时总是先运行
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    High,
+    Normal,
+    Low,
+    /// 只用于 idle 任务，不会进入就绪队列
+    Idle,
+}
+
+impl Priority {
+    /// 每次被调度时得到的时间片 (tick 数)
+    pub const fn time_slice(&self) -> usize {
+        match self {
+            Priority::High => 8,
+            Priority::Normal => 4,
+            Priority::Low => 2,
+            Priority::Idle => 1,
+        }
+    }
+}
+
+/// 就绪队列，每个优先级类别一个队列，同一类别内轮转
+pub struct RunQueue {
+    queues: [VecDeque<Arc<Task>>; 3],
+}
+
+impl RunQueue {
+    pub const fn new() -> Self {
+        Self {
+            queues: [VecDeque::new(), VecDeque::new(), VecDeque::new()],
+        }
+    }
+
+    /// 加入对应优先级队列的末尾，idle 任务不会加入
+    pub fn push(&mut self, task: Arc<Task>) {
+        if let Some(queue) = self.queues.get_mut(task.priority as usize) {
+            queue.push_back(task);
+        }
+    }
+
+    /// 取出最高优先级队列中的第一个任务
+    pub fn pop(&mut self) -> Option<Arc<Task>> {
+        self.queues.iter_mut().find_map(|queue| queue.pop_front())
+    }
+
+    /// 就绪任务中的最高优先级
+    pub fn highest_priority(&self) -> Option<Priority> {
+        self.queues
+            .iter()
+            .position(|queue| !queue.is_empty())
+            .map(|index| [Priority::High, Priority::Normal, Priority::Low][index])
+    }
+
+    pub fn len(&self) -> usize {
+        self.queues.iter().map(|queue| queue.len()).sum()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<Task>> {
+        self.queues.iter().flatten()
+    }
+}
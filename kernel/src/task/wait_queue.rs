@@ -0,0 +1,50 @@
+use alloc::{sync::Arc, vec::Vec};
+use core::sync::atomic::Ordering;
+use spin::Mutex;
+
+use super::{current, schedule, wake, Task, TaskState};
+use crate::trap::without_interrupts;
+
+/// 等待队列，任务在条件满足之前阻塞在这里
+///
+/// 也可以在中断处理函数中唤醒
+pub struct WaitQueue {
+    tasks: Mutex<Vec<Arc<Task>>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self {
+            tasks: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// 阻塞当前任务直到 cond 返回 Some
+    ///
+    /// 先加入队列再检查条件，检查之后的唤醒不会丢失。cond 在打开中断时调用
+    pub fn wait_until<T>(&self, mut cond: impl FnMut() -> Option<T>) -> T {
+        let task = current().expect("wait before task::init");
+        loop {
+            task.woken.store(false, Ordering::Relaxed);
+            without_interrupts(|| self.tasks.lock().push(task.clone()));
+            if let Some(value) = cond() {
+                without_interrupts(|| self.tasks.lock().retain(|x| !Arc::ptr_eq(x, &task)));
+                return value;
+            }
+            without_interrupts(|| {
+                if !task.woken.load(Ordering::Relaxed) {
+                    schedule(TaskState::Blocked);
+                }
+            });
+        }
+    }
+
+    /// 唤醒所有等待的任务
+    pub fn wake_all(&self) {
+        without_interrupts(|| {
+            for task in self.tasks.lock().drain(..) {
+                wake(task);
+            }
+        });
+    }
+}
@@ -0,0 +1,141 @@
+//! 时钟中断与系统时钟
+//!
+//! 通过 SBI 设置下一次时钟中断，每次中断 tick 计数加一。
+
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use fdt::Fdt;
+use log::info;
+
+use crate::sbi::{self, EXTENSION_TIMER};
+use crate::task;
+
+/// 默认每秒时钟中断次数
+const DEFAULT_TICKS_PER_SEC: usize = 100;
+
+/// sie 寄存器中的 STIE 位
+const SIE_STIE: usize = 1 << 5;
+
+/// sstatus 寄存器中的 SIE 位
+const SSTATUS_SIE: usize = 1 << 1;
+
+/// 时钟频率 (time 寄存器每秒增加的值)
+static TIMEBASE_FREQ: AtomicUsize = AtomicUsize::new(0);
+
+/// 每秒时钟中断次数
+static TICKS_PER_SEC: AtomicUsize = AtomicUsize::new(DEFAULT_TICKS_PER_SEC);
+
+/// 启动以来的 tick 数
+static TICKS: AtomicUsize = AtomicUsize::new(0);
+
+/// SBI 是否支持 TIME 扩展
+static HAS_TIME_EXT: AtomicBool = AtomicBool::new(false);
+
+/// 读取 time 寄存器
+#[inline]
+pub fn get_time() -> usize {
+    let time: usize;
+    unsafe { asm!("rdtime {}", out(reg) time) };
+    time
+}
+
+/// 时钟频率
+#[inline]
+pub fn timebase_freq() -> usize {
+    TIMEBASE_FREQ.load(Ordering::Relaxed)
+}
+
+/// 每秒 tick 数
+#[inline]
+pub fn ticks_per_sec() -> usize {
+    TICKS_PER_SEC.load(Ordering::Relaxed)
+}
+
+/// 启动以来的 tick 数
+#[inline]
+pub fn ticks() -> usize {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// 启动以来经过的时间 (tick 精度)
+pub fn uptime() -> Duration {
+    let ticks = ticks() as u64;
+    let hz = ticks_per_sec() as u64;
+    Duration::new(ticks / hz, ((ticks % hz) * 1_000_000_000 / hz) as u32)
+}
+
+/// 等待直到 tick 数到达 target
+///
+/// 任务系统初始化后当前任务进入睡眠，由时钟中断唤醒，否则等待下一次中断
+pub fn sleep_until(target: usize) {
+    if task::current().is_some() {
+        task::sleep_until(target);
+        return;
+    }
+    while ticks() < target {
+        unsafe { asm!("wfi") };
+    }
+}
+
+/// 设置下一次时钟中断
+fn set_next_timeout() {
+    let next = get_time() + timebase_freq() / ticks_per_sec();
+    if HAS_TIME_EXT.load(Ordering::Relaxed) {
+        sbi::set_timer_ext(next);
+    } else {
+        sbi::set_timer(next);
+    }
+}
+
+/// 时钟中断处理
+pub fn handle_tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    set_next_timeout();
+}
+
+/// 从设备树 /cpus 节点中读取 timebase-frequency
+fn read_timebase_freq(fdt: &Fdt) -> usize {
+    fdt.find_node("/cpus")
+        .and_then(|cpus| cpus.property("timebase-frequency"))
+        .and_then(|prop| prop.as_usize())
+        .unwrap_or_else(|| {
+            fdt.cpus()
+                .next()
+                .expect("can't find cpu node in device tree")
+                .timebase_frequency()
+        })
+}
+
+/// 初始化时钟中断
+///
+/// tick 频率可以在编译时通过环境变量 TICK_HZ 设置
+pub fn init(fdt: &Fdt) {
+    let ticks_per_sec = option_env!("TICK_HZ")
+        .and_then(|hz| hz.parse().ok())
+        .filter(|hz| *hz > 0)
+        .unwrap_or(DEFAULT_TICKS_PER_SEC);
+    TICKS_PER_SEC.store(ticks_per_sec, Ordering::Relaxed);
+    TIMEBASE_FREQ.store(read_timebase_freq(fdt), Ordering::Relaxed);
+    HAS_TIME_EXT.store(sbi::probe_extension(EXTENSION_TIMER), Ordering::Relaxed);
+
+    info!(
+        "timer: timebase {} Hz, {} ticks per second, sbi time extension: {}",
+        timebase_freq(),
+        ticks_per_sec,
+        HAS_TIME_EXT.load(Ordering::Relaxed)
+    );
+
+    set_next_timeout();
+    unsafe {
+        asm!(
+            "csrs sie, {sie}",
+            "csrs sstatus, {sstatus}",
+            sie = in(reg) SIE_STIE,
+            sstatus = in(reg) SSTATUS_SIE,
+        );
+    }
+}
@@ -0,0 +1,289 @@
+//! 中断与异常处理
+//!
+//! 通过 stvec 设置统一的陷入入口 trap_vector，入口处把所有通用寄存器和
+//! sepc/sstatus/scause/stval 保存到栈上的 TrapFrame 中，然后交给 rust 处理。
+
+use core::arch::{asm, global_asm};
+
+use log::{error, warn};
+
+use crate::{plic, process, syscall, task, timer, tty, uart};
+
+/// scause 中断位
+const SCAUSE_INTERRUPT: usize = 1 << 63;
+
+/// sstatus 寄存器中的 SIE 位
+const SSTATUS_SIE: usize = 1 << 1;
+
+/// sstatus 寄存器中的 SPIE 位
+const SSTATUS_SPIE: usize = 1 << 5;
+
+/// sstatus 寄存器中的 SPP 位，陷入前处于内核态时为 1
+const SSTATUS_SPP: usize = 1 << 8;
+
+/// 陷入时保存的上下文
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TrapFrame {
+    /// 通用寄存器 x0 - x31
+    pub x: [usize; 32],
+    /// 陷入时的 PC
+    pub sepc: usize,
+    /// 陷入时的状态寄存器
+    pub sstatus: usize,
+    /// 陷入原因
+    pub scause: usize,
+    /// 附加信息 (出错地址或指令)
+    pub stval: usize,
+}
+
+/// 异常类型
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exception {
+    InstructionMisaligned,
+    InstructionFault,
+    IllegalInstruction,
+    Breakpoint,
+    LoadMisaligned,
+    LoadFault,
+    StoreMisaligned,
+    StoreFault,
+    UserEnvCall,
+    SupervisorEnvCall,
+    InstructionPageFault,
+    LoadPageFault,
+    StorePageFault,
+    Unknown(usize),
+}
+
+/// 中断类型
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interrupt {
+    SupervisorSoft,
+    SupervisorTimer,
+    SupervisorExternal,
+    Unknown(usize),
+}
+
+/// 解析后的陷入原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trap {
+    Interrupt(Interrupt),
+    Exception(Exception),
+}
+
+impl Trap {
+    /// 从 scause 解析陷入原因
+    pub fn from_scause(scause: usize) -> Self {
+        let code = scause & !SCAUSE_INTERRUPT;
+        if scause & SCAUSE_INTERRUPT != 0 {
+            Trap::Interrupt(match code {
+                1 => Interrupt::SupervisorSoft,
+                5 => Interrupt::SupervisorTimer,
+                9 => Interrupt::SupervisorExternal,
+                _ => Interrupt::Unknown(code),
+            })
+        } else {
+            Trap::Exception(match code {
+                0 => Exception::InstructionMisaligned,
+                1 => Exception::InstructionFault,
+                2 => Exception::IllegalInstruction,
+                3 => Exception::Breakpoint,
+                4 => Exception::LoadMisaligned,
+                5 => Exception::LoadFault,
+                6 => Exception::StoreMisaligned,
+                7 => Exception::StoreFault,
+                8 => Exception::UserEnvCall,
+                9 => Exception::SupervisorEnvCall,
+                12 => Exception::InstructionPageFault,
+                13 => Exception::LoadPageFault,
+                15 => Exception::StorePageFault,
+                _ => Exception::Unknown(code),
+            })
+        }
+    }
+}
+
+impl TrapFrame {
+    /// 创建一个从 entry 开始运行用户程序的上下文，返回用户态后打开中断
+    pub fn new_user(entry: usize, sp: usize) -> Self {
+        let sstatus: usize;
+        unsafe { asm!("csrr {}, sstatus", out(reg) sstatus) };
+        let mut tf = Self {
+            sepc: entry,
+            sstatus: (sstatus & !(SSTATUS_SPP | SSTATUS_SIE)) | SSTATUS_SPIE,
+            ..Default::default()
+        };
+        tf.x[2] = sp;
+        tf
+    }
+
+    /// 是否从用户态陷入
+    pub fn is_user(&self) -> bool {
+        self.sstatus & SSTATUS_SPP == 0
+    }
+
+    /// 跳过触发异常的指令 (兼容压缩指令)
+    pub fn skip_instruction(&mut self) {
+        let inst = unsafe { (self.sepc as *const u16).read_volatile() };
+        self.sepc += if inst & 0b11 == 0b11 { 4 } else { 2 };
+    }
+}
+
+// 陷入入口，stvec 要求地址 4 字节对齐
+//
+// 运行在内核态时 sscratch 为 0，运行在用户态时 sscratch 为当前任务的内核栈栈顶。
+// 从用户态陷入时交换 sp 和 sscratch 切换到内核栈，返回用户态前重新设置 sscratch。
+global_asm!(
+    "
+    .section .text
+    .globl trap_vector
+    .align 2
+trap_vector:
+    csrrw   sp, sscratch, sp
+    bnez    sp, 1f
+    csrrw   sp, sscratch, sp            // 来自内核态，换回原来的 sp
+1:
+    addi    sp, sp, -{trapframe_size}
+    sd      x1, 1*8(sp)
+    .irp n, 3,4,5,6,7,8,9,10,11,12,13,14,15,16,17,18,19,20,21,22,23,24,25,26,27,28,29,30,31
+    sd      x\\n, \\n*8(sp)
+    .endr
+
+    csrrw   t0, sscratch, zero          // 用户态的 sp，来自内核态时为 0
+    bnez    t0, 2f
+    addi    t0, sp, {trapframe_size}    // 陷入前的 sp
+2:
+    sd      t0, 2*8(sp)
+    csrr    t0, sepc
+    sd      t0, 32*8(sp)
+    csrr    t0, sstatus
+    sd      t0, 33*8(sp)
+    csrr    t0, scause
+    sd      t0, 34*8(sp)
+    csrr    t0, stval
+    sd      t0, 35*8(sp)
+
+    mv      a0, sp
+    call    trap_handler
+    mv      a0, sp
+
+    .globl trap_return
+trap_return:
+    mv      sp, a0
+    ld      t0, 32*8(sp)
+    csrw    sepc, t0
+    ld      t0, 33*8(sp)
+    csrw    sstatus, t0
+    andi    t0, t0, {sstatus_spp}
+    bnez    t0, 3f
+    addi    t0, sp, {trapframe_size}    // 返回用户态，下次陷入时使用的内核栈
+    csrw    sscratch, t0
+3:
+    ld      x1, 1*8(sp)
+    .irp n, 3,4,5,6,7,8,9,10,11,12,13,14,15,16,17,18,19,20,21,22,23,24,25,26,27,28,29,30,31
+    ld      x\\n, \\n*8(sp)
+    .endr
+    ld      sp, 2*8(sp)
+    sret
+    ",
+    trapframe_size = const core::mem::size_of::<TrapFrame>(),
+    sstatus_spp = const SSTATUS_SPP,
+);
+
+/// rust 陷入处理函数，由 trap_vector 调用
+#[no_mangle]
+extern "C" fn trap_handler(tf: &mut TrapFrame) {
+    let trap = Trap::from_scause(tf.scause);
+    match trap {
+        Trap::Interrupt(Interrupt::SupervisorTimer) => {
+            timer::handle_tick();
+            // 串口中断没有通过 PLIC 注册时，在时钟中断中检查输入
+            if !uart::is_irq_driven() {
+                uart::handle_irq();
+                tty::handle_input();
+            }
+            // 时间片用完时在这里切换到其他任务，切换回来后再从陷入中返回
+            task::scheduler_tick();
+        }
+        Trap::Interrupt(Interrupt::SupervisorExternal) => plic::handle_irq(),
+        Trap::Exception(Exception::UserEnvCall) => {
+            tf.sepc += 4;
+            syscall::syscall(tf);
+        }
+        Trap::Exception(
+            Exception::InstructionPageFault | Exception::LoadPageFault | Exception::StorePageFault,
+        ) if tf.is_user() => process::handle_page_fault(trap, tf),
+        Trap::Exception(_) if tf.is_user() => process::handle_user_exception(trap, tf),
+        Trap::Exception(Exception::Breakpoint) => {
+            warn!("breakpoint at {:#x}", tf.sepc);
+            tf.skip_instruction();
+        }
+        _ => {
+            error!(
+                "unhandled trap {:?}, scause: {:#x}, sepc: {:#x}, stval: {:#x}",
+                trap, tf.scause, tf.sepc, tf.stval
+            );
+            error!("{:#x?}", tf);
+            panic!("unhandled trap {:?} at {:#x}", trap, tf.sepc);
+        }
+    }
+}
+
+/// 初始化中断，设置 stvec 为 trap_vector (direct 模式)
+pub fn init() {
+    extern "C" {
+        fn trap_vector();
+    }
+    unsafe {
+        asm!(
+            "csrw stvec, {0}",
+            "csrw sscratch, zero",
+            in(reg) trap_vector as usize,
+        );
+    }
+}
+
+/// 按照内核栈顶的 TrapFrame 恢复寄存器并通过 sret 返回，用于第一次进入用户态
+///
+/// trap_return 把 TrapFrame 之后的地址作为下次陷入时的内核栈，
+/// 所以 TrapFrame 必须正好位于 stack_top 之下
+pub fn enter_user(stack_top: usize) -> ! {
+    extern "C" {
+        fn trap_return(tf: *const TrapFrame) -> !;
+    }
+    // 设置 sscratch 之后不能再发生中断
+    unsafe { asm!("csrc sstatus, {}", in(reg) SSTATUS_SIE) };
+    unsafe { trap_return((stack_top - core::mem::size_of::<TrapFrame>()) as *const TrapFrame) }
+}
+
+/// 打开中断
+#[inline]
+pub fn enable_interrupts() {
+    unsafe { asm!("csrs sstatus, {}", in(reg) SSTATUS_SIE) };
+}
+
+/// 关闭中断，返回之前中断是否打开
+#[inline]
+pub fn disable_interrupts() -> bool {
+    let sstatus: usize;
+    unsafe { asm!("csrrc {}, sstatus, {}", out(reg) sstatus, in(reg) SSTATUS_SIE) };
+    sstatus & SSTATUS_SIE != 0
+}
+
+/// 恢复 disable_interrupts 之前的中断状态
+#[inline]
+pub fn restore_interrupts(enabled: bool) {
+    if enabled {
+        enable_interrupts();
+    }
+}
+
+/// 关闭中断执行 f，结束后恢复原来的中断状态
+#[inline]
+pub fn without_interrupts<T>(f: impl FnOnce() -> T) -> T {
+    let enabled = disable_interrupts();
+    let ret = f();
+    restore_interrupts(enabled);
+    ret
+}
@@ -0,0 +1,206 @@
+//! 控制台终端 (TTY)
+//!
+//! 从串口 (串口初始化之前从 SBI) 读取输入，经过行规程处理后放入输入缓冲区。
+//! 规范模式下按行编辑，支持退格、Ctrl-U 删除整行、Ctrl-C 中断，输入回车后整行才能被读取；
+//! 原始模式下每个字节直接可以读取。输入在中断处理中处理，读取时没有输入的任务会阻塞。
+
+use alloc::{string::String, vec::Vec};
+use core::mem;
+
+use spin::Mutex;
+
+use crate::ring_buffer::RingBuffer;
+use crate::task::WaitQueue;
+use crate::trap::without_interrupts;
+use crate::{sbi, uart};
+
+const CTRL_C: u8 = 0x03;
+const BACKSPACE: u8 = 0x08;
+const CTRL_U: u8 = 0x15;
+const DELETE: u8 = 0x7f;
+
+/// 输入缓冲区大小
+const INPUT_SIZE: usize = 4096;
+
+/// 规范模式下一行的最大长度，超出的输入被丢弃
+const LINE_MAX: usize = 1024;
+
+/// 输入模式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// 按行编辑，输入回车后整行才能被读取
+    Canonical,
+    /// 每个字节直接可以读取，不处理控制字符
+    Raw,
+}
+
+/// 读取时按下了 Ctrl-C
+#[derive(Debug)]
+pub struct Interrupted;
+
+struct Tty {
+    mode: Mode,
+    echo: bool,
+    /// 可以读取的输入
+    input: RingBuffer<INPUT_SIZE>,
+    /// 规范模式下正在编辑的行
+    line: [u8; LINE_MAX],
+    line_len: usize,
+    /// 按下了 Ctrl-C，下一次读取返回 Interrupted
+    interrupted: bool,
+}
+
+impl Tty {
+    const fn new() -> Self {
+        Self {
+            mode: Mode::Canonical,
+            echo: true,
+            input: RingBuffer::new(),
+            line: [0; LINE_MAX],
+            line_len: 0,
+            interrupted: false,
+        }
+    }
+
+    fn echo(&self, bytes: &[u8]) {
+        if self.echo {
+            crate::put_bytes(bytes);
+        }
+    }
+
+    /// 删除正在编辑的行中的最后 count 个字节
+    fn erase(&mut self, count: usize) {
+        for _ in 0..count.min(self.line_len) {
+            self.line_len -= 1;
+            self.echo(b"\x08 \x08");
+        }
+    }
+
+    /// 处理一个输入字节
+    fn receive(&mut self, byte: u8) {
+        if self.mode == Mode::Raw {
+            self.input.push(byte);
+            self.echo(&[byte]);
+            return;
+        }
+        match byte {
+            b'\r' | b'\n' => {
+                for byte in &self.line[..self.line_len] {
+                    self.input.push(*byte);
+                }
+                self.input.push(b'\n');
+                self.line_len = 0;
+                self.echo(b"\r\n");
+            }
+            BACKSPACE | DELETE => self.erase(1),
+            CTRL_U => self.erase(self.line_len),
+            CTRL_C => {
+                self.line_len = 0;
+                self.interrupted = true;
+                self.echo(b"^C\r\n");
+            }
+            _ if self.line_len < LINE_MAX => {
+                self.line[self.line_len] = byte;
+                self.line_len += 1;
+                self.echo(&[byte]);
+            }
+            _ => {}
+        }
+    }
+}
+
+static TTY: Mutex<Tty> = Mutex::new(Tty::new());
+
+/// 等待输入的任务
+static INPUT_READY: WaitQueue = WaitQueue::new();
+
+/// 读取一个原始输入字节，串口初始化之前通过 SBI 读取
+fn getchar() -> Option<u8> {
+    if uart::is_initialized() {
+        uart::getchar()
+    } else {
+        sbi::console_getchar()
+    }
+}
+
+/// 处理所有已经到达的输入，在中断处理中调用
+pub fn handle_input() {
+    let mut received = false;
+    while let Some(byte) = getchar() {
+        without_interrupts(|| TTY.lock().receive(byte));
+        received = true;
+    }
+    if received {
+        INPUT_READY.wake_all();
+    }
+}
+
+pub fn mode() -> Mode {
+    without_interrupts(|| TTY.lock().mode)
+}
+
+/// 切换输入模式，切换到原始模式时正在编辑的行直接变为可读取
+pub fn set_mode(mode: Mode) {
+    without_interrupts(|| {
+        let mut tty = TTY.lock();
+        if tty.mode == Mode::Canonical && mode == Mode::Raw {
+            for i in 0..mem::take(&mut tty.line_len) {
+                let byte = tty.line[i];
+                tty.input.push(byte);
+            }
+        }
+        tty.mode = mode;
+    });
+    INPUT_READY.wake_all();
+}
+
+pub fn echo() -> bool {
+    without_interrupts(|| TTY.lock().echo)
+}
+
+pub fn set_echo(echo: bool) {
+    without_interrupts(|| TTY.lock().echo = echo);
+}
+
+/// 读取输入到 buf 中，返回读取的字节数，没有输入时阻塞
+///
+/// 规范模式下最多读取一行，读到换行符为止
+pub fn read(buf: &mut [u8]) -> Result<usize, Interrupted> {
+    if buf.is_empty() {
+        return Ok(0);
+    }
+    INPUT_READY.wait_until(|| {
+        without_interrupts(|| {
+            let mut tty = TTY.lock();
+            if mem::take(&mut tty.interrupted) {
+                return Some(Err(Interrupted));
+            }
+            let canonical = tty.mode == Mode::Canonical;
+            let mut len = 0;
+            while len < buf.len() {
+                let Some(byte) = tty.input.pop() else {
+                    break;
+                };
+                buf[len] = byte;
+                len += 1;
+                if canonical && byte == b'\n' {
+                    break;
+                }
+            }
+            (len > 0).then_some(Ok(len))
+        })
+    })
+}
+
+/// 读取一行输入，不包括换行符，按下 Ctrl-C 时返回 None
+pub fn read_line() -> Option<String> {
+    let mut line = Vec::new();
+    let mut byte = [0];
+    loop {
+        read(&mut byte).ok()?;
+        if byte[0] == b'\n' {
+            return Some(String::from_utf8_lossy(&line).into_owned());
+        }
+        line.push(byte[0]);
+    }
+}
@@ -0,0 +1,241 @@
+//! NS16550A 串口驱动
+//!
+//! 从设备树中找到 ns16550a 节点。输出先放入发送缓冲区，由发送 FIFO 变空的中断写入下一批；
+//! 输入由中断处理函数 handle_irq 读入接收缓冲区。串口中断通过 PLIC 注册，
+//! 没有 PLIC 时由时钟中断轮询调用 handle_irq，这时输出和 panic 之后的输出一样同步写入。
+//! 串口初始化之前 (启动早期) 的输出仍然通过 SBI。
+
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use fdt::Fdt;
+use log::{info, warn};
+use spin::Mutex;
+
+use crate::page_table::paddr_to_virt;
+use crate::plic;
+use crate::ring_buffer::RingBuffer;
+use crate::trap::without_interrupts;
+use crate::tty;
+
+/// 接收缓冲寄存器 (读)
+const RBR: usize = 0;
+/// 发送保持寄存器 (写)
+const THR: usize = 0;
+/// 中断使能寄存器
+const IER: usize = 1;
+/// FIFO 控制寄存器 (写)
+const FCR: usize = 2;
+/// 线路控制寄存器
+const LCR: usize = 3;
+/// Modem 控制寄存器
+const MCR: usize = 4;
+/// 线路状态寄存器
+const LSR: usize = 5;
+
+/// 接收到数据时产生中断
+const IER_RX_AVAILABLE: u8 = 1 << 0;
+/// 发送 FIFO 为空时产生中断
+const IER_THR_EMPTY: u8 = 1 << 1;
+/// 打开并清空收发 FIFO
+const FCR_ENABLE_CLEAR: u8 = 0b111;
+/// 8 位数据位，无校验，1 位停止位
+const LCR_8N1: u8 = 0b11;
+/// 一些平台上需要设置 OUT2 才会输出中断信号
+const MCR_OUT2: u8 = 1 << 3;
+/// 接收缓冲中有数据
+const LSR_DATA_READY: u8 = 1 << 0;
+/// 发送 FIFO 为空
+const LSR_THR_EMPTY: u8 = 1 << 5;
+/// 发送 FIFO 和移位寄存器都为空，数据已经全部发出
+const LSR_TX_IDLE: u8 = 1 << 6;
+
+/// 发送 FIFO 的大小，为空时可以连续写入这么多字节
+const FIFO_SIZE: usize = 16;
+
+const TX_BUFFER_SIZE: usize = 4096;
+const RX_BUFFER_SIZE: usize = 1024;
+
+struct Uart {
+    base: usize,
+    /// 寄存器间隔为 1 << reg_shift 字节
+    reg_shift: usize,
+    tx: RingBuffer<TX_BUFFER_SIZE>,
+    rx: RingBuffer<RX_BUFFER_SIZE>,
+}
+
+impl Uart {
+    fn read_reg(&self, reg: usize) -> u8 {
+        unsafe { read_volatile((self.base + (reg << self.reg_shift)) as *const u8) }
+    }
+
+    fn write_reg(&self, reg: usize, value: u8) {
+        unsafe { write_volatile((self.base + (reg << self.reg_shift)) as *mut u8, value) }
+    }
+
+    /// 波特率沿用固件的设置
+    fn init(&self) {
+        self.write_reg(IER, 0);
+        self.write_reg(LCR, LCR_8N1);
+        self.write_reg(FCR, FCR_ENABLE_CLEAR);
+        self.write_reg(MCR, MCR_OUT2);
+        self.write_reg(IER, IER_RX_AVAILABLE);
+    }
+
+    /// 发送 FIFO 为空时从发送缓冲区写入一批数据，缓冲区中还有数据时打开发送中断
+    fn fill_fifo(&mut self) {
+        if self.read_reg(LSR) & LSR_THR_EMPTY != 0 {
+            for _ in 0..FIFO_SIZE {
+                match self.tx.pop() {
+                    Some(byte) => self.write_reg(THR, byte),
+                    None => break,
+                }
+            }
+        }
+        let ier = if self.tx.is_empty() {
+            IER_RX_AVAILABLE
+        } else {
+            IER_RX_AVAILABLE | IER_THR_EMPTY
+        };
+        self.write_reg(IER, ier);
+    }
+
+    /// 轮询写出发送缓冲区中的数据，等待全部发出
+    fn flush(&mut self) {
+        while !self.tx.is_empty() {
+            self.fill_fifo();
+        }
+        while self.read_reg(LSR) & LSR_TX_IDLE == 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    /// 把收到的数据读入接收缓冲区，缓冲区满时丢弃
+    ///
+    /// 持有 UART 的锁时不能输出日志
+    fn drain_rx(&mut self) {
+        while self.read_reg(LSR) & LSR_DATA_READY != 0 {
+            let byte = self.read_reg(RBR);
+            self.rx.push(byte);
+        }
+    }
+}
+
+static UART: Mutex<Option<Uart>> = Mutex::new(None);
+
+/// 串口中断是否已经通过 PLIC 注册
+static IRQ_DRIVEN: AtomicBool = AtomicBool::new(false);
+
+/// panic 之后不再等待中断，输出都同步写入
+static POLLED: AtomicBool = AtomicBool::new(false);
+
+/// 从设备树中找到串口并初始化，之后的输出都通过串口
+pub fn init(fdt: &Fdt) {
+    let Some(node) = fdt.find_compatible(&["ns16550a", "ns16550"]) else {
+        warn!("uart: no ns16550a in device tree, using sbi console");
+        return;
+    };
+    let Some(region) = node.reg().and_then(|mut reg| reg.next()) else {
+        warn!("uart: {} has no reg property", node.name);
+        return;
+    };
+    let uart = Uart {
+        base: paddr_to_virt(region.starting_address as usize),
+        reg_shift: node
+            .property("reg-shift")
+            .and_then(|prop| prop.as_usize())
+            .unwrap_or(0),
+        tx: RingBuffer::new(),
+        rx: RingBuffer::new(),
+    };
+    uart.init();
+    let irq = plic::irq_of(&node);
+    info!(
+        "uart: {} at {:#x}, irq {:?}",
+        node.name,
+        region.starting_address as usize,
+        irq
+    );
+    without_interrupts(|| *UART.lock() = Some(uart));
+    if let Some(irq) = irq {
+        IRQ_DRIVEN.store(plic::register_handler(irq, irq_handler), Ordering::Relaxed);
+    }
+}
+
+/// 通过串口输出，串口还没有初始化时返回 false
+///
+/// 数据放入发送缓冲区后返回，缓冲区满时等待 FIFO 腾出空间。
+/// 串口中断不可用或 panic 之后等待数据全部发出后返回
+pub fn put_bytes(bytes: &[u8]) -> bool {
+    without_interrupts(|| {
+        let mut uart = UART.lock();
+        let Some(uart) = uart.as_mut() else {
+            return false;
+        };
+        for byte in bytes {
+            while !uart.tx.push(*byte) {
+                uart.fill_fifo();
+            }
+        }
+        uart.fill_fifo();
+        if !is_irq_driven() || POLLED.load(Ordering::Relaxed) {
+            uart.flush();
+        }
+        true
+    })
+}
+
+/// 之后的输出都同步写入，并写出发送缓冲区中剩余的数据，panic 时调用
+///
+/// panic 可能发生在持有串口锁的时候，这时不等待锁，剩余的数据被丢弃
+pub fn set_polled() {
+    POLLED.store(true, Ordering::Relaxed);
+    without_interrupts(|| {
+        if let Some(uart) = UART.try_lock().as_mut().and_then(|uart| uart.as_mut()) {
+            uart.flush();
+        }
+    });
+}
+
+/// 等待发送缓冲区中的数据全部发出，关机前调用
+pub fn flush() {
+    without_interrupts(|| {
+        if let Some(uart) = UART.lock().as_mut() {
+            uart.flush();
+        }
+    });
+}
+
+/// 读取一个输入字节，没有输入或串口还没有初始化时返回 None
+pub fn getchar() -> Option<u8> {
+    without_interrupts(|| {
+        let mut uart = UART.lock();
+        let uart = uart.as_mut()?;
+        uart.drain_rx();
+        uart.rx.pop()
+    })
+}
+
+/// 串口是否已经初始化
+pub fn is_initialized() -> bool {
+    without_interrupts(|| UART.lock().is_some())
+}
+
+/// 串口中断是否通过 PLIC 处理，否则需要轮询
+pub fn is_irq_driven() -> bool {
+    IRQ_DRIVEN.load(Ordering::Relaxed)
+}
+
+/// PLIC 中的串口中断处理函数
+fn irq_handler() {
+    handle_irq();
+    tty::handle_input();
+}
+
+/// 串口中断处理，读入收到的数据，继续发送缓冲区中的数据
+pub fn handle_irq() {
+    if let Some(uart) = UART.lock().as_mut() {
+        uart.drain_rx();
+        uart.fill_fifo();
+    }
+}